@@ -0,0 +1,155 @@
+use crate::consumer::Consumer;
+use crate::error::{Error, Result};
+use crate::index::read_record_at;
+use crate::message::{Message, MessageId};
+use crate::record::*;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+pub const VISIBILITY_TIMEOUT: Duration = Duration::from_secs(30);
+pub const MAX_IN_FLIGHT: usize = 1000;
+
+// Message given to the caller and not acked yet, or acked after a message that is not acked
+struct InFlight {
+    pos: u64,
+    len: u32,
+    // the message is given again after the deadline if it is not acked
+    deadline: Instant,
+    attempts: u32,
+    acked: bool,
+}
+
+// Consumer with acknowledgement of every message. The persisted position of the consumer is moved
+// only past the messages acked without gaps, messages that are not acked in the visibility timeout are given again
+pub struct AckConsumer {
+    consumer: Consumer,
+    // position after the last read record: part id, position, count of read records of the part
+    part_id: u32,
+    pos: u64,
+    count: u32,
+    in_flight: BTreeMap<MessageId, InFlight>,
+    visibility_timeout: Duration,
+    max_in_flight: usize,
+}
+
+impl AckConsumer {
+    pub fn new(base_path: &str, consumer_name: &str, queue_name: &str) -> Result<AckConsumer> {
+        let consumer = Consumer::new(base_path, consumer_name, queue_name)?;
+        let (part_id, pos, count) = consumer.position();
+
+        Ok(AckConsumer {
+            consumer,
+            part_id,
+            pos,
+            count,
+            in_flight: BTreeMap::new(),
+            visibility_timeout: VISIBILITY_TIMEOUT,
+            max_in_flight: MAX_IN_FLIGHT,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.consumer.name
+    }
+
+    pub fn set_visibility_timeout(&mut self, timeout: Duration) {
+        self.visibility_timeout = timeout;
+    }
+
+    pub fn set_max_in_flight(&mut self, max: usize) {
+        self.max_in_flight = max.max(1);
+    }
+
+    // Number of times the message in flight was given to the caller
+    pub fn attempts(&self, id: MessageId) -> u32 {
+        self.in_flight.get(&id).map_or(0, |m| m.attempts)
+    }
+
+    pub fn count_in_flight(&self) -> usize {
+        self.in_flight.values().filter(|m| !m.acked).count()
+    }
+
+    // Returns a message whose visibility timeout is expired or the next message of the queue.
+    // None if there are no such messages, InFlightLimit if the limit of messages in flight is reached
+    pub fn next_message(&mut self) -> Result<Option<Message>> {
+        let now = Instant::now();
+
+        let (id, pos) = match self.in_flight.iter_mut().find(|(_, m)| !m.acked && m.deadline <= now) {
+            Some((id, m)) => {
+                m.attempts += 1;
+                m.deadline = now + self.visibility_timeout;
+                debug!("[queue:ack] ({}): redeliver {}:{}, attempt {}", self.consumer.name, id.part_id, id.seq, m.attempts);
+                (*id, m.pos)
+            },
+            None => {
+                if self.in_flight.len() >= self.max_in_flight {
+                    return Err(Error::new(ErrorQueue::InFlightLimit, "next message, limit of messages in flight is reached"));
+                }
+
+                match self.consumer.queue.next_record_position(self.part_id, self.pos, self.count)? {
+                    Some((part_id, pos, count)) => (
+                        MessageId {
+                            part_id,
+                            seq: count + 1,
+                        },
+                        pos,
+                    ),
+                    None => return Ok(None),
+                }
+            },
+        };
+
+        let (header, data) = read_record_at(&self.consumer.queue, id.part_id, pos, id.seq)?;
+
+        if !self.in_flight.contains_key(&id) {
+            self.part_id = id.part_id;
+            self.pos = pos + HEADER_SIZE as u64 + header.msg_length as u64;
+            self.count = id.seq;
+            self.in_flight.insert(
+                id,
+                InFlight {
+                    pos,
+                    len: header.msg_length,
+                    deadline: now + self.visibility_timeout,
+                    attempts: 1,
+                    acked: false,
+                },
+            );
+        }
+
+        Ok(Some(Message::new(id.part_id, header, data)))
+    }
+
+    // Marks the message as processed, the persisted position is moved past the messages acked without gaps
+    pub fn ack(&mut self, id: MessageId) -> Result<()> {
+        match self.in_flight.get_mut(&id) {
+            Some(m) => m.acked = true,
+            None => return Err(Error::new(ErrorQueue::NotFound, "ack, message is not in flight").with_part(id.part_id)),
+        }
+
+        let mut cursor = None;
+        while let Some(entry) = self.in_flight.first_entry() {
+            if !entry.get().acked {
+                break;
+            }
+            let (id, m) = entry.remove_entry();
+            cursor = Some((id.part_id, m.pos + HEADER_SIZE as u64 + m.len as u64, id.seq));
+        }
+
+        match cursor {
+            Some((part_id, pos, count)) => self.consumer.commit_position(part_id, pos, count),
+            None => Ok(()),
+        }
+    }
+
+    // Returns the message for processing again after the delay
+    pub fn nack(&mut self, id: MessageId, delay: Duration) -> Result<()> {
+        match self.in_flight.get_mut(&id) {
+            Some(m) if !m.acked => {
+                m.deadline = Instant::now() + delay;
+                Ok(())
+            },
+            _ => Err(Error::new(ErrorQueue::NotFound, "nack, message is not in flight").with_part(id.part_id)),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use crate::consumer::Consumer;
+use crate::error::{Error, Result};
+use crate::message::Message;
+use crate::queue::Queue;
+use crate::record::*;
+use futures_core::Stream;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::future::{poll_fn, Future};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::spawn_blocking;
+
+// Safety net for lost or unsupported file change notifications
+const WAIT_NOTIFY_TIMEOUT: Duration = Duration::from_millis(1000);
+
+type NextMessage = Pin<Box<dyn Future<Output = Result<Message>> + Send>>;
+
+pub struct AsyncQueue {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl AsyncQueue {
+    pub async fn new(base_path: &str, queue_name: &str, mode: Mode) -> Result<AsyncQueue> {
+        let base_path = base_path.to_owned();
+        let queue_name = queue_name.to_owned();
+
+        let queue = spawn_blocking(move || Queue::new(&base_path, &queue_name, mode)).await.map_err(|_| task_error("open queue"))??;
+
+        Ok(AsyncQueue {
+            queue: Arc::new(Mutex::new(queue)),
+        })
+    }
+
+    pub async fn push(&self, data: &[u8], msg_type: MsgType) -> Result<u64> {
+        let queue = self.queue.clone();
+        let data = data.to_vec();
+
+        spawn_blocking(move || match queue.lock() {
+            Ok(mut q) => q.push(&data, msg_type),
+            Err(_) => Err(Error::new(ErrorQueue::NotReady, "push, queue lock is poisoned")),
+        })
+        .await
+        .map_err(|_| task_error("push"))?
+    }
+}
+
+pub struct AsyncConsumer {
+    consumer: Arc<Mutex<Consumer>>,
+    notify: Arc<Notify>,
+    next_message: Option<NextMessage>,
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl AsyncConsumer {
+    pub async fn new(base_path: &str, consumer_name: &str, queue_name: &str) -> Result<AsyncConsumer> {
+        let (path, c_name, q_name) = (base_path.to_owned(), consumer_name.to_owned(), queue_name.to_owned());
+
+        let consumer = spawn_blocking(move || Consumer::new(&path, &c_name, &q_name)).await.map_err(|_| task_error("open consumer"))??;
+
+        let notify = Arc::new(Notify::new());
+        let watcher = watch_queue(base_path, queue_name, notify.clone());
+
+        Ok(AsyncConsumer {
+            consumer: Arc::new(Mutex::new(consumer)),
+            notify,
+            next_message: None,
+            _watcher: watcher,
+        })
+    }
+
+    pub async fn next(&mut self) -> Result<Message> {
+        poll_fn(|cx| self.poll_next_message(cx)).await
+    }
+
+    pub async fn commit(&self) -> Result<()> {
+        let consumer = self.consumer.clone();
+
+        spawn_blocking(move || match consumer.lock() {
+            Ok(mut c) => c.commit(),
+            Err(_) => Err(Error::new(ErrorQueue::NotReady, "commit, consumer lock is poisoned")),
+        })
+        .await
+        .map_err(|_| task_error("commit"))?
+    }
+
+    fn poll_next_message(&mut self, cx: &mut Context<'_>) -> Poll<Result<Message>> {
+        let (consumer, notify) = (self.consumer.clone(), self.notify.clone());
+        let next_message = self.next_message.get_or_insert_with(|| Box::pin(wait_message(consumer, notify)));
+
+        match next_message.as_mut().poll(cx) {
+            Poll::Ready(res) => {
+                self.next_message = None;
+                Poll::Ready(res)
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Stream for AsyncConsumer {
+    type Item = Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_next_message(cx).map(Some)
+    }
+}
+
+async fn wait_message(consumer: Arc<Mutex<Consumer>>, notify: Arc<Notify>) -> Result<Message> {
+    loop {
+        let c = consumer.clone();
+        let res = spawn_blocking(move || match c.lock() {
+            Ok(mut c) => c.next_message(),
+            Err(_) => Err(Error::new(ErrorQueue::NotReady, "read message, consumer lock is poisoned")),
+        })
+        .await
+        .map_err(|_| task_error("read message"))?;
+
+        if let Some(msg) = res? {
+            return Ok(msg);
+        }
+
+        let _ = tokio::time::timeout(WAIT_NOTIFY_TIMEOUT, notify.notified()).await;
+    }
+}
+
+fn task_error(op: &'static str) -> Error {
+    Error::new(ErrorQueue::Other, op)
+}
+
+fn watch_queue(base_path: &str, queue_name: &str, notify: Arc<Notify>) -> Option<RecommendedWatcher> {
+    let info_push = queue_name.to_owned() + "_info_push";
+    let info_queue = queue_name.to_owned() + "_info_queue";
+
+    let handler = move |res: notify::Result<Event>| {
+        if let Ok(event) = res {
+            if !matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_)) {
+                return;
+            }
+            let is_queue_info = event.paths.iter().filter_map(|p| p.file_name()).any(|f| *f == *info_push || *f == *info_queue);
+            if is_queue_info {
+                notify.notify_one();
+            }
+        }
+    };
+
+    match notify::recommended_watcher(handler) {
+        Ok(mut watcher) => {
+            if let Err(e) = watcher.watch(Path::new(base_path), RecursiveMode::Recursive) {
+                warn!("[queue:async] fail watch {}, use polling, err={}", base_path, e);
+                return None;
+            }
+            Some(watcher)
+        },
+        Err(e) => {
+            warn!("[queue:async] fail create watcher, use polling, err={}", e);
+            None
+        },
+    }
+}
@@ -0,0 +1,210 @@
+use crate::checkpoint::replace_file;
+use crate::consumer::{CommitPolicy, Consumer};
+use crate::error::{Error, Result};
+use crate::message::Message;
+use crate::queue::Queue;
+use crate::record::*;
+use crc32fast::Hasher;
+use std::fs::*;
+use std::io::ErrorKind;
+
+// Batch of the bridge: the output records are published when the output part has out_count records,
+// the input position after the batch is in_part, in_pos, in_count
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub(crate) struct BridgeState {
+    pub(crate) out_part: u32,
+    pub(crate) out_count: u32,
+    pub(crate) in_part: u32,
+    pub(crate) in_pos: u64,
+    pub(crate) in_count: u32,
+}
+
+// Consume-transform-produce stage between two queues. The outputs of a batch of input messages are pushed
+// in one transaction of the output queue, and the state of the batch is written before the transaction is committed.
+// After a restart the input position is moved past the batch if its outputs are published, so every input message
+// gives its outputs once
+pub struct Bridge {
+    pub name: String,
+    pub(crate) consumer: Consumer,
+    pub(crate) output: Queue,
+    state_file_name: String,
+}
+
+impl Bridge {
+    // The input is read by the consumer with the name of the bridge, the output queue is opened for writing
+    pub fn new(name: &str, input_base_path: &str, input_queue: &str, output_base_path: &str, output_queue: &str) -> Result<Bridge> {
+        let mut consumer = Consumer::new(input_base_path, name, input_queue)?;
+        consumer.set_commit_policy(CommitPolicy::Manual);
+        consumer.commit_on_next_part = false;
+        let output = Queue::new(output_base_path, output_queue, Mode::ReadWrite)?;
+
+        let mut bridge = Bridge {
+            name: name.to_owned(),
+            consumer,
+            state_file_name: output_base_path.to_owned() + "/" + output_queue + "_bridge_" + name,
+            output,
+        };
+        bridge.reconcile()?;
+        Ok(bridge)
+    }
+
+    pub fn consumer(&self) -> &Consumer {
+        &self.consumer
+    }
+
+    pub fn output(&self) -> &Queue {
+        &self.output
+    }
+
+    // Reads up to max_messages input messages and pushes the outputs given by the transform for them,
+    // returns the number of processed input messages
+    pub fn process<F>(&mut self, max_messages: usize, transform: F) -> Result<usize>
+    where
+        F: FnMut(&Message) -> Vec<(Vec<u8>, MsgType)>,
+    {
+        let (count, state) = self.prepare(max_messages, transform)?;
+        if let Some(state) = state {
+            self.finish(state)?;
+        }
+        Ok(count)
+    }
+
+    // Pushes the outputs of the batch in a transaction and writes the state of the batch, the transaction is not committed
+    pub(crate) fn prepare<F>(&mut self, max_messages: usize, mut transform: F) -> Result<(usize, Option<BridgeState>)>
+    where
+        F: FnMut(&Message) -> Vec<(Vec<u8>, MsgType)>,
+    {
+        let start = self.consumer.position();
+        self.output.begin()?;
+
+        let count = match self.push_outputs(max_messages, &mut transform) {
+            Ok(0) => {
+                self.output.abort()?;
+                return Ok((0, None));
+            },
+            Ok(count) => count,
+            Err(e) => {
+                self.rollback(start);
+                return Err(e);
+            },
+        };
+
+        let (in_part, in_pos, in_count) = self.consumer.position();
+        let state = BridgeState {
+            out_part: self.output.id,
+            out_count: self.output.count_pushed,
+            in_part,
+            in_pos,
+            in_count,
+        };
+        if let Err(e) = self.write_state(&state) {
+            self.rollback(start);
+            return Err(e);
+        }
+
+        Ok((count, Some(state)))
+    }
+
+    // Publishes the outputs, they are synced to disk before the input position is committed
+    pub(crate) fn finish(&mut self, state: BridgeState) -> Result<()> {
+        self.output.commit()?;
+        self.consumer.commit_position(state.in_part, state.in_pos, state.in_count)
+    }
+
+    fn push_outputs<F>(&mut self, max_messages: usize, transform: &mut F) -> Result<usize>
+    where
+        F: FnMut(&Message) -> Vec<(Vec<u8>, MsgType)>,
+    {
+        let mut count = 0;
+        while count < max_messages {
+            let message = match self.consumer.next_message()? {
+                Some(m) => m,
+                None => break,
+            };
+            for (data, msg_type) in transform(&message) {
+                self.output.push(&data, msg_type)?;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // Drops the outputs of the failed batch, its input messages are given again
+    fn rollback(&mut self, (part_id, pos, count): (u32, u64, u32)) {
+        if let Err(e) = self.output.abort() {
+            error!("[queue:bridge] {}: fail abort output transaction, err={}", self.name, e);
+        }
+        if let Err(e) = self.consumer.set_position(part_id, pos, count) {
+            error!("[queue:bridge] {}: fail return input to part {}, count {}, err={}", self.name, part_id, count, e);
+        }
+    }
+
+    // Moves the input position past the last batch if its outputs are published and the input was not committed
+    fn reconcile(&mut self) -> Result<()> {
+        let state = match self.read_state()? {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        let is_published = match self.output.read_info_of_part(state.out_part) {
+            Ok((_, count_pushed)) => count_pushed >= state.out_count,
+            Err(e) if e.kind() == ErrorQueue::NotFound => false,
+            Err(e) => return Err(e),
+        };
+
+        let (part_id, _, count) = self.consumer.position();
+        if is_published && (part_id, count) < (state.in_part, state.in_count) {
+            warn!(
+                "[queue:bridge] {}: outputs of the last batch are published, move input from part {}, count {} to part {}, count {}",
+                self.name, part_id, count, state.in_part, state.in_count
+            );
+            self.consumer.commit_position(state.in_part, state.in_pos, state.in_count)?;
+        }
+        Ok(())
+    }
+
+    // <out part>;<out count>;<in part>;<in pos>;<in count>;<crc>
+    fn write_state(&self, state: &BridgeState) -> Result<()> {
+        let p = format!("{};{};{};{};{};", state.out_part, state.out_count, state.in_part, state.in_pos, state.in_count);
+        let mut hash = Hasher::new();
+        hash.update(p.as_bytes());
+
+        if let Err(e) = replace_file(&self.state_file_name, format!("{}{}\n", p, hash.finalize()).as_bytes(), false) {
+            return Err(Error::io(ErrorQueue::FailWrite, "write bridge state", &self.state_file_name, e));
+        }
+        Ok(())
+    }
+
+    fn read_state(&self) -> Result<Option<BridgeState>> {
+        let content = match read_to_string(&self.state_file_name) {
+            Ok(c) => c,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::io(ErrorQueue::FailRead, "read bridge state", &self.state_file_name, e)),
+        };
+
+        let line = content.lines().next().unwrap_or_default();
+        let invalid = |msg| Error::new(ErrorQueue::Other, msg).with_path(&self.state_file_name);
+
+        let (p, crc) = match line.rsplit_once(';') {
+            Some((p, crc)) => (p, crc),
+            None => return Err(invalid("read bridge state, invalid format")),
+        };
+        let mut hash = Hasher::new();
+        hash.update(p.as_bytes());
+        hash.update(b";");
+        if crc.parse::<u32>().ok() != Some(hash.finalize()) {
+            return Err(invalid("read bridge state, invalid checksum"));
+        }
+
+        match scan_fmt!(p, "{};{};{};{};{}", u32, u32, u32, u64, u32) {
+            Ok((out_part, out_count, in_part, in_pos, in_count)) => Ok(Some(BridgeState {
+                out_part,
+                out_count,
+                in_part,
+                in_pos,
+                in_count,
+            })),
+            Err(_) => Err(invalid("read bridge state, invalid format")),
+        }
+    }
+}
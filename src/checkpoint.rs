@@ -0,0 +1,177 @@
+use crate::error::{Error, Result};
+use crate::record::*;
+use crc32fast::Hasher;
+use std::fs::*;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::path::Path;
+
+pub const PREV_SUFFIX: &str = ".prev";
+pub const TMP_SUFFIX: &str = ".tmp";
+// earlier versions kept the positions of the consumers of all queues in <base path>/individuals-flow_info_pop_<consumer>
+pub const LEGACY_INFO_POP_PREFIX: &str = "individuals-flow_info_pop_";
+
+// Position of a consumer, persisted in the _info_pop file as <queue>;<consumer>;<pos>;<count popped>;<part id>;<crc>,
+// the crc is computed as in _info_push. The previous checkpoint is kept in the file with the suffix .prev
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Checkpoint {
+    pub part_id: u32,
+    pub pos: u64,
+    pub count_popped: u32,
+}
+
+// Replaces the checkpoint at once: the new checkpoint is written to a temporary file and synced,
+// the current checkpoint becomes the previous one, and the temporary file is renamed to the checkpoint
+pub(crate) fn write_checkpoint(file_name: &str, queue_name: &str, consumer_name: &str, checkpoint: &Checkpoint) -> Result<()> {
+    let p = format!("{};{};{};{};{};", queue_name, consumer_name, checkpoint.pos, checkpoint.count_popped, checkpoint.part_id);
+    let mut hash = Hasher::new();
+    hash.update(p.as_bytes());
+    let content = format!("{}{}\n", p, hash.finalize());
+
+    if let Err(e) = replace_file(file_name, content.as_bytes(), true) {
+        return Err(Error::io(ErrorQueue::FailWrite, "write checkpoint", file_name, e).with_part(checkpoint.part_id));
+    }
+    Ok(())
+}
+
+// Reads the checkpoint, a corrupted checkpoint is replaced by the previous one.
+// None if there is no checkpoint yet
+pub(crate) fn read_checkpoint(file_name: &str, queue_name: &str, consumer_name: &str) -> Result<Option<Checkpoint>> {
+    let current = parse_checkpoint(file_name, queue_name, consumer_name);
+    if let Ok(Some(checkpoint)) = current {
+        return Ok(Some(checkpoint));
+    }
+
+    let prev_file_name = file_name.to_owned() + PREV_SUFFIX;
+    match parse_checkpoint(&prev_file_name, queue_name, consumer_name) {
+        Ok(Some(checkpoint)) => {
+            match &current {
+                Err(e) => warn!("[queue:checkpoint] {}, use previous checkpoint {}", e, prev_file_name),
+                Ok(_) => warn!("[queue:checkpoint] {} is not found, use previous checkpoint {}", file_name, prev_file_name),
+            }
+            Ok(Some(checkpoint))
+        },
+        _ => current,
+    }
+}
+
+pub(crate) fn checkpoint_exists(file_name: &str) -> bool {
+    Path::new(file_name).exists() || Path::new(&(file_name.to_owned() + PREV_SUFFIX)).exists()
+}
+
+// Moves the position of the consumer of the queue from the legacy file, if the legacy file holds it
+// and it is newer than the checkpoint. Legacy files of the consumers of other queues are left
+pub(crate) fn migrate_legacy_checkpoint(base_path: &str, file_name: &str, queue_name: &str, consumer_name: &str) -> Result<()> {
+    let legacy_file_name = base_path.to_owned() + "/" + LEGACY_INFO_POP_PREFIX + consumer_name;
+    if legacy_file_name == file_name {
+        return Ok(());
+    }
+
+    let legacy = match parse_checkpoint(&legacy_file_name, queue_name, consumer_name) {
+        Ok(Some(checkpoint)) => checkpoint,
+        _ => return Ok(()),
+    };
+
+    let modified = |name: &str| metadata(name).and_then(|m| m.modified()).ok();
+    let is_newer = match (modified(&legacy_file_name), modified(file_name)) {
+        (Some(legacy_time), Some(time)) => legacy_time > time,
+        _ => true,
+    };
+
+    if is_newer || !checkpoint_exists(file_name) {
+        write_checkpoint(file_name, queue_name, consumer_name, &legacy)?;
+        info!("[queue:checkpoint] {}:{} migrate position from {} to {}", queue_name, consumer_name, legacy_file_name, file_name);
+    }
+
+    if let Err(e) = remove_file(&legacy_file_name) {
+        warn!("[queue:checkpoint] {}:{} fail remove legacy file {}, err={}", queue_name, consumer_name, legacy_file_name, e);
+    }
+    Ok(())
+}
+
+// Writes the content to a temporary file, syncs it and renames it to the file, the file is either old or new after a crash.
+// The replaced file is kept with the suffix .prev if keep_prev is set
+pub(crate) fn replace_file(file_name: &str, content: &[u8], keep_prev: bool) -> std::io::Result<()> {
+    let tmp_file_name = file_name.to_owned() + TMP_SUFFIX;
+    File::create(&tmp_file_name)
+        .and_then(|mut f| {
+            f.write_all(content)?;
+            f.sync_all()
+        })
+        .and_then(|_| match keep_prev {
+            true => keep_previous(file_name),
+            false => Ok(()),
+        })
+        .and_then(|_| rename(&tmp_file_name, file_name))
+        .and_then(|_| sync_dir(file_name))
+}
+
+// Removes the checkpoint with its previous and temporary files
+pub(crate) fn remove_checkpoint(file_name: &str) -> Result<()> {
+    for name in [file_name.to_owned() + TMP_SUFFIX, file_name.to_owned() + PREV_SUFFIX, file_name.to_owned()] {
+        if let Err(e) = remove_file(&name) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(Error::io(ErrorQueue::FailWrite, "remove checkpoint", name, e));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_checkpoint(file_name: &str, queue_name: &str, consumer_name: &str) -> Result<Option<Checkpoint>> {
+    let content = match read_to_string(file_name) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::io(ErrorQueue::FailRead, "read checkpoint", file_name, e)),
+    };
+
+    let line = match content.lines().next() {
+        Some(l) if !l.is_empty() => l,
+        _ => return Ok(None),
+    };
+
+    let invalid = |msg| Error::new(ErrorQueue::Other, msg).with_path(file_name);
+
+    // checkpoints of earlier versions have no crc
+    let (fields, crc) = match line.rsplit_once(';') {
+        Some((p, crc)) if line.split(';').count() == 6 => (p, Some(crc)),
+        _ => (line, None),
+    };
+
+    if let Some(crc) = crc {
+        let mut hash = Hasher::new();
+        hash.update(fields.as_bytes());
+        hash.update(b";");
+        if crc.parse::<u32>().ok() != Some(hash.finalize()) {
+            return Err(invalid("read checkpoint, invalid checksum"));
+        }
+    }
+
+    match scan_fmt!(fields, "{};{};{};{};{}", String, String, u64, u32, u32) {
+        Ok((q, c, pos, count_popped, part_id)) => {
+            if q != queue_name || c != consumer_name {
+                return Err(invalid("read checkpoint, name of queue or consumer mismatch"));
+            }
+            Ok(Some(Checkpoint {
+                part_id,
+                pos,
+                count_popped,
+            }))
+        },
+        Err(_) => Err(invalid("read checkpoint, invalid format")),
+    }
+}
+
+fn keep_previous(file_name: &str) -> std::io::Result<()> {
+    match rename(file_name, file_name.to_owned() + PREV_SUFFIX) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn sync_dir(file_name: &str) -> std::io::Result<()> {
+    match Path::new(file_name).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => Ok(()),
+    }
+}
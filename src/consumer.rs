@@ -1,13 +1,69 @@
+use crate::checkpoint::*;
+use crate::dlq::*;
+use crate::error::{Error, Result};
+use crate::filter::MessageFilter;
+use crate::index::{read_record_at, IndexEntry, PartIndex};
+use crate::message::{Message, Messages};
+use crate::mmap::PartMap;
 use crate::queue::*;
 use crate::record::*;
+#[cfg(feature = "schema")]
+use crate::schema::SchemaIds;
+use crate::time_index::*;
+use crate::watch::InfoWatcher;
 use crc32fast::Hasher;
 use fs2::FileExt;
 use std::cmp::Ordering;
 use std::fs::*;
 use std::io::prelude::*;
 use std::io::SeekFrom;
-use std::io::{BufRead, BufReader};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const WAIT_POLL_MIN: Duration = Duration::from_millis(1);
+const WAIT_POLL_MAX: Duration = Duration::from_millis(100);
+
+// State of the consumer after a failed read or commit, a consumer in a state other than Ready
+// doesn't read and commit until Consumer::recover is called
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ConsumerState {
+    Ready,
+    // the body of the current record has an invalid checksum, the position is after the record
+    InvalidRecord,
+    // a file of the queue or of the consumer can't be opened or written
+    Failed(ErrorQueue),
+}
+
+// When the position of the consumer is persisted without an explicit call of Consumer::commit.
+// Messages read after the last persisted position are read again after a restart
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum CommitPolicy {
+    // only Consumer::commit persists the position
+    Manual,
+    // before reading the next message, only the message in processing can be read again
+    EveryMessage,
+    // before reading the next message when N messages are read since the last commit
+    EveryN(u32),
+    // before reading the next message when the interval is passed since the last commit
+    Interval(Duration),
+    // when the consumer is dropped, all messages read by the consumer can be read again
+    OnDrop,
+}
+
+// Where an ephemeral consumer starts reading
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum StartPosition {
+    // the first record of the first part
+    Head,
+    // after the last record, only new messages are read
+    Tail,
+    // the record with the sequence number in the current part of the queue
+    Sequence(u32),
+}
+
+pub const EPHEMERAL_CONSUMER_NAME: &str = "ephemeral";
 
 pub struct Consumer {
     mode: Mode,
@@ -16,10 +72,27 @@ pub struct Consumer {
     pub count_popped: u32,
     pub id: u32,
 
-    is_ready: bool,
+    state: ConsumerState,
     pos_record: u64,
-    ff_info_pop: File,
+    lock_file: Option<File>,
     base_path: String,
+    use_dead_letter_queue: bool,
+    dead_letter_queue: Option<Arc<Mutex<Queue>>>,
+    use_mmap: bool,
+    part_map: Option<PartMap>,
+    watcher: Option<InfoWatcher>,
+    use_polling: bool,
+    commit_policy: CommitPolicy,
+    count_committed: u32,
+    last_commit: Instant,
+    // the position is kept only in memory
+    ephemeral: bool,
+    // the position is persisted when the next part is opened, the bridge persists it only with its outputs
+    pub(crate) commit_on_next_part: bool,
+    // records not matching the filter are skipped without reading their bodies
+    filter: Option<MessageFilter>,
+    #[cfg(feature = "schema")]
+    schema_ids: SchemaIds,
 
     // tmp
     pub header: Header,
@@ -29,104 +102,160 @@ pub struct Consumer {
 impl Drop for Consumer {
     fn drop(&mut self) {
         if self.mode != Mode::Read {
+            // a panic in processing of the last message must not commit it
+            if self.commit_policy != CommitPolicy::Manual && self.state == ConsumerState::Ready && self.count_uncommitted() > 0 && !thread::panicking() {
+                if let Err(e) = self.commit() {
+                    error!("[queue:consumer] drop: queue:{}:{}:{}, fail commit, err={}", self.queue.name, self.queue.id, self.name, e);
+                }
+            }
+
             let info_name_lock = self.base_path.to_owned() + "/" + &self.queue.name + "_info_pop_" + &self.name + ".lock";
             if let Err(e) = remove_file(&info_name_lock) {
                 error!("[queue:consumer] drop: queue:{}:{}:{}, fail remove lock file {}, err={:?}", self.queue.name, self.queue.id, self.name, &info_name_lock, e);
             }
+            if let Some(lock_file) = &self.lock_file {
+                if let Err(e) = FileExt::unlock(lock_file) {
+                    debug!("[queue:consumer] drop: queue:{}:{}:{}, fail unlock, err={}", self.queue.name, self.queue.id, self.name, e);
+                }
+            }
         }
     }
 }
 
 impl Consumer {
-    pub fn new(base_path: &str, consumer_name: &str, queue_name: &str) -> Result<Consumer, ErrorQueue> {
+    pub fn new(base_path: &str, consumer_name: &str, queue_name: &str) -> Result<Consumer> {
         Consumer::new_with_mode(base_path, consumer_name, queue_name, Mode::ReadWrite)
     }
 
-    pub fn new_with_mode(base_path: &str, consumer_name: &str, queue_name: &str, mode: Mode) -> Result<Consumer, ErrorQueue> {
-        let info_name = base_path.to_owned() + "/individuals-flow_info_pop_" + consumer_name;
-        let exists = Path::new(&info_name).exists();
+    pub fn new_with_mode(base_path: &str, consumer_name: &str, queue_name: &str, mode: Mode) -> Result<Consumer> {
+        let info_name = base_path.to_owned() + "/" + queue_name + "_info_pop_" + consumer_name;
 
         match Queue::new(base_path, queue_name, Mode::Read) {
             Ok(mut q) => {
                 if !q.get_info_queue() {
-                    return Err(ErrorQueue::NotReady);
+                    return Err(Error::new(ErrorQueue::NotReady, "consumer, get info of queue").with_path(q.info_queue_file_name()));
                 }
 
+                // the lock is held while the consumer is alive
+                let mut lock_file = None;
                 if mode == Mode::ReadWrite {
                     let info_name_lock = base_path.to_owned() + "/" + queue_name + "_info_pop_" + consumer_name + ".lock";
 
-                    match OpenOptions::new().read(true).write(true).create(true).open(info_name_lock) {
+                    match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&info_name_lock) {
                         Ok(file) => {
-                            if let Err(e) = file.lock_exclusive() {
+                            if let Err(e) = file.try_lock_exclusive() {
                                 error!("consumer:{} attempt lock, err={}", consumer_name, e);
-                                return Err(ErrorQueue::AlreadyOpen);
+                                return Err(Error::io(ErrorQueue::AlreadyOpen, "lock consumer", info_name_lock, e));
                             }
+                            lock_file = Some(file);
                         },
                         Err(e) => {
                             error!("consumer:{} prepare lock, err={}", consumer_name, e);
-                            return Err(ErrorQueue::FailOpen);
+                            return Err(Error::io(ErrorQueue::FailOpen, "open consumer lock", info_name_lock, e));
                         },
                     }
                 }
 
-                let open_with_option = if mode == Mode::ReadWrite {
-                    OpenOptions::new().read(true).write(true).create(true).open(&info_name)
-                } else {
-                    OpenOptions::new().read(true).open(&info_name)
-                };
-
-                match open_with_option {
-                    Ok(ff) => {
-                        let mut consumer = Consumer {
-                            mode,
-                            is_ready: true,
-                            name: consumer_name.to_owned(),
-                            ff_info_pop: ff,
-                            queue: q,
-                            count_popped: 0,
-                            pos_record: 0,
-                            hash: Hasher::new(),
-                            header: Header {
-                                start_pos: 0,
-                                msg_length: 0,
-                                magic_marker: 0,
-                                count_pushed: 0,
-                                crc: 0,
-                                msg_type: MsgType::String,
-                            },
-                            base_path: base_path.to_string(),
-                            id: 0,
-                        };
-
-                        if exists && consumer.get_info() {
-                            // Существующий консьюмер - используем сохраненную позицию
-                            if consumer.queue.open_part(consumer.id).is_ok() {
-                                if consumer.queue.ff_queue.seek(SeekFrom::Start(consumer.pos_record)).is_err() {
-                                    return Err(ErrorQueue::NotReady);
-                                }
-                            }
-                        } else {
-                            // Новый консьюмер - начинаем с текущей части
-                            consumer.id = consumer.queue.id;
-                            consumer.pos_record = 0;
-                            consumer.count_popped = 0;
+                if mode == Mode::ReadWrite {
+                    migrate_legacy_checkpoint(base_path, &info_name, queue_name, consumer_name)?;
+                }
 
-                            if consumer.queue.open_part(consumer.id).is_err() {
-                                return Err(ErrorQueue::NotReady);
-                            }
+                let exists = checkpoint_exists(&info_name);
+                if mode == Mode::Read && !exists {
+                    return Err(Error::new(ErrorQueue::NotReady, "open consumer info, checkpoint not found").with_path(info_name));
+                }
 
-                            consumer.open(true);
-                            if !consumer.commit() {
-                                return Err(ErrorQueue::NotReady);
-                            }
+                let mut consumer = Consumer::create(base_path, consumer_name, mode, lock_file, q);
+
+                if exists {
+                    // Существующий консьюмер - используем сохраненную позицию,
+                    // непрочитанный checkpoint - ошибка, иначе консьюмер пропустит непрочитанные сообщения
+                    consumer.get_info()?;
+                    consumer.count_committed = consumer.count_popped;
+                    if consumer.queue.open_part(consumer.id).is_ok() {
+                        if let Err(e) = consumer.queue.ff_queue.seek(SeekFrom::Start(consumer.pos_record)) {
+                            return Err(Error::io(ErrorQueue::NotReady, "consumer, seek to position", consumer.queue.part_file_name(consumer.id), e)
+                                .with_part(consumer.id));
                         }
+                    }
+                } else {
+                    // Новый консьюмер - начинаем с текущей части
+                    consumer.id = consumer.queue.id;
+                    consumer.pos_record = 0;
+                    consumer.count_popped = 0;
+
+                    consumer.queue.open_part(consumer.id)?;
 
-                        Ok(consumer)
-                    },
-                    Err(_e) => Err(ErrorQueue::NotReady),
+                    consumer.open(true)?;
+                    consumer.commit()?;
                 }
+
+                Ok(consumer)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    // Follows the queue without the _info_pop and lock files, the position is kept only in memory and is lost when the consumer is dropped
+    pub fn ephemeral(base_path: &str, queue_name: &str, start: StartPosition) -> Result<Consumer> {
+        let mut q = Queue::new(base_path, queue_name, Mode::Read)?;
+        if !q.get_info_queue() {
+            return Err(Error::new(ErrorQueue::NotReady, "ephemeral consumer, get info of queue").with_path(q.info_queue_file_name()));
+        }
+
+        let mut consumer = Consumer::create(base_path, EPHEMERAL_CONSUMER_NAME, Mode::Read, None, q);
+        consumer.ephemeral = true;
+        consumer.id = consumer.queue.id;
+        consumer.queue.open_part(consumer.id)?;
+
+        match start {
+            StartPosition::Head => consumer.seek_to_start()?,
+            StartPosition::Tail => consumer.seek_to_end()?,
+            StartPosition::Sequence(seq) => consumer.seek_to_sequence(seq)?,
+        }
+
+        debug!("[queue:consumer] ephemeral consumer of {} starts at part {}, count popped {}", queue_name, consumer.id, consumer.count_popped);
+        Ok(consumer)
+    }
+
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    fn create(base_path: &str, name: &str, mode: Mode, lock_file: Option<File>, queue: Queue) -> Consumer {
+        Consumer {
+            mode,
+            state: ConsumerState::Ready,
+            name: name.to_owned(),
+            lock_file,
+            queue,
+            count_popped: 0,
+            pos_record: 0,
+            hash: Hasher::new(),
+            header: Header {
+                start_pos: 0,
+                msg_length: 0,
+                magic_marker: 0,
+                count_pushed: 0,
+                crc: 0,
+                msg_type: MsgType::String,
             },
-            Err(_e) => Err(ErrorQueue::NotReady),
+            base_path: base_path.to_string(),
+            use_dead_letter_queue: false,
+            dead_letter_queue: None,
+            use_mmap: false,
+            part_map: None,
+            watcher: None,
+            use_polling: false,
+            commit_policy: CommitPolicy::Manual,
+            count_committed: 0,
+            last_commit: Instant::now(),
+            ephemeral: false,
+            commit_on_next_part: true,
+            filter: None,
+            #[cfg(feature = "schema")]
+            schema_ids: SchemaIds::default(),
+            id: 0,
         }
     }
 
@@ -148,7 +277,7 @@ impl Consumer {
                 self.queue.get_info_queue();
 
                 if self.queue.id > self.id {
-                    if level == 0 && self.go_to_next_part() {
+                    if level == 0 && matches!(self.go_to_next_part(), Ok(true)) {
                         return self.get_batch_size_l(level + 1);
                     }
 
@@ -157,7 +286,7 @@ impl Consumer {
             },
             Ordering::Greater => {
                 return if self.queue.id != self.id {
-                    if level == 0 && self.go_to_next_part() {
+                    if level == 0 && matches!(self.go_to_next_part(), Ok(true)) {
                         return self.get_batch_size_l(level + 1);
                     }
 
@@ -171,79 +300,280 @@ impl Consumer {
         0
     }
 
-    pub fn open(&mut self, is_new: bool) -> bool {
-        if !self.queue.is_ready {
-            error!("[queue:consumer] open: queue not ready, set consumer.ready = false");
-            self.is_ready = false;
-            return false;
+    pub fn wait_for_messages(&mut self, timeout: Duration) -> u32 {
+        if let Err(e) = self.commit_by_policy() {
+            error!("[queue:consumer] fail commit before wait, err={}", e);
         }
 
-        let info_pop_file_name = self.queue.base_path.to_owned() + "/" + &self.queue.name + "_info_pop_" + &self.name;
+        let deadline = Instant::now() + timeout;
+        let mut poll_interval = WAIT_POLL_MIN;
 
-        let open_with_option = if self.mode == Mode::ReadWrite {
-            OpenOptions::new().read(true).write(true).truncate(true).create(is_new).open(&info_pop_file_name)
-        } else {
-            OpenOptions::new().read(true).open(&info_pop_file_name)
-        };
+        loop {
+            // arm the watcher before checking, so a push between the check and the wait is not lost
+            let use_watcher = self.prepare_watcher();
 
-        if let Ok(ff) = open_with_option {
-            self.ff_info_pop = ff;
-        } else {
-            error!("[queue:consumer] open: fail open file [{}], set consumer.ready = false", info_pop_file_name);
-            self.is_ready = false;
+            let size = self.get_available_size();
+            if size > 0 {
+                return size;
+            }
+
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return 0;
+            }
+
+            if use_watcher {
+                if let Some(w) = &mut self.watcher {
+                    if let Err(e) = w.wait(left) {
+                        error!("[queue:consumer] fail wait for queue changes, use polling, err={}", e);
+                        self.watcher = None;
+                        self.use_polling = true;
+                    }
+                }
+            } else {
+                thread::sleep(poll_interval.min(left));
+                poll_interval = (poll_interval * 2).min(WAIT_POLL_MAX);
+            }
+        }
+    }
+
+    fn prepare_watcher(&mut self) -> bool {
+        if self.use_polling {
             return false;
         }
+
+        if self.watcher.is_none() {
+            match InfoWatcher::new(&self.queue) {
+                Ok(w) => self.watcher = Some(w),
+                Err(e) => {
+                    warn!("[queue:consumer] file change notifications are not available, use polling, err={}", e);
+                    self.use_polling = true;
+                    return false;
+                },
+            }
+        }
+
+        if let Some(w) = &mut self.watcher {
+            if let Err(e) = w.watch_part(&self.queue, self.id) {
+                debug!("[queue:consumer] fail watch part {}, err={}", self.id, e);
+                return false;
+            }
+        }
         true
     }
 
-    pub fn get_info(&mut self) -> bool {
-        let mut res = true;
+    fn get_available_size(&mut self) -> u32 {
+        if self.queue.get_info_of_part(self.id, false).is_ok() && self.queue.count_pushed > self.count_popped {
+            return self.queue.count_pushed - self.count_popped;
+        }
 
-        if self.ff_info_pop.seek(SeekFrom::Start(0)).is_err() {
-            return false;
+        self.get_batch_size()
+    }
+
+    pub fn open(&mut self, is_new: bool) -> Result<()> {
+        let info_pop_file_name = self.info_pop_file_name();
+
+        if !self.queue.is_ready {
+            error!("[queue:consumer] open: queue not ready");
+            self.state = ConsumerState::Failed(ErrorQueue::NotReady);
+            return Err(Error::new(ErrorQueue::NotReady, "open consumer info, queue not ready").with_path(info_pop_file_name));
         }
 
-        if let Some(line) = BufReader::new(&self.ff_info_pop).lines().next() {
-            if let Ok(ll) = line {
-                if let Ok((queue_name, consumer_name, position, count_popped, id)) = scan_fmt!(&ll, "{};{};{};{};{}", String, String, u64, u32, u32) {
-                    if queue_name != self.queue.name {
-                        res = false;
-                    }
+        // the checkpoint is replaced by commit, an existing consumer must have it
+        if (self.mode == Mode::Read || !is_new) && !self.ephemeral && !checkpoint_exists(&info_pop_file_name) {
+            error!("[queue:consumer] open: checkpoint [{}] not found", info_pop_file_name);
+            self.state = ConsumerState::Failed(ErrorQueue::FailOpen);
+            return Err(Error::new(ErrorQueue::FailOpen, "open consumer info, checkpoint not found").with_path(info_pop_file_name));
+        }
 
-                    if consumer_name != self.name {
-                        res = false;
-                    }
+        Ok(())
+    }
 
-                    self.pos_record = position;
-                    self.count_popped = count_popped;
-                    self.id = id;
-                } else {
-                    res = false;
+    pub fn get_info(&mut self) -> Result<()> {
+        let info_pop_file_name = self.info_pop_file_name();
+        if self.ephemeral {
+            return Err(Error::new(ErrorQueue::NotFound, "get consumer info, consumer is ephemeral"));
+        }
+
+        match read_checkpoint(&info_pop_file_name, &self.queue.name, &self.name)? {
+            Some(checkpoint) => {
+                self.pos_record = checkpoint.pos;
+                self.count_popped = checkpoint.count_popped;
+                self.id = checkpoint.part_id;
+                debug!("[queue:consumer] ({}): count_popped:{}, position:{}, id:{}", self.name, self.count_popped, self.pos_record, self.id);
+                Ok(())
+            },
+            None => Err(Error::new(ErrorQueue::NotFound, "get consumer info, checkpoint not found").with_path(info_pop_file_name)),
+        }
+    }
+
+    pub fn state(&self) -> ConsumerState {
+        self.state
+    }
+
+    // Returns the consumer to the Ready state: a record with an invalid checksum is skipped,
+    // after a failure the files of the current part and the consumer info are reopened
+    pub fn recover(&mut self) -> Result<()> {
+        match self.state {
+            ConsumerState::Ready => return Ok(()),
+            ConsumerState::InvalidRecord => {
+                warn!("[queue:consumer] skip record pos={}, count_pushed={}", self.header.start_pos, self.header.count_pushed);
+                self.count_popped += 1;
+            },
+            ConsumerState::Failed(_) => {
+                // the queue of the consumer is opened for reading, its latch can be reset
+                self.queue.is_ready = true;
+                self.queue.open_part(self.id)?;
+                self.part_map = None;
+                if self.mode == Mode::ReadWrite {
+                    self.open(true)?;
                 }
-            } else {
-                return false;
-            }
+            },
         }
 
-        debug!("[queue:consumer] ({}): count_pushed:{}, position:{}, id:{}, success:{}", self.name, self.count_popped, self.pos_record, self.id, res);
-        res
+        self.state = ConsumerState::Ready;
+        self.sync_and_set_cur_pos();
+
+        if self.mode == Mode::ReadWrite {
+            self.commit()?;
+        }
+        Ok(())
     }
 
-    pub fn pop_header(&mut self) -> bool {
-        let res = self.read_header();
+    // Returns the header of the next record, None if there are no new records. The consumer stays ready
+    // after ErrorQueue::InvalidHeader, the next call continues from the next found record
+    pub fn pop_header(&mut self) -> Result<Option<Header>> {
+        if self.state != ConsumerState::Ready {
+            return Err(self.error(ErrorQueue::NotReady, "pop header"));
+        }
+
+        self.commit_by_policy()?;
 
-        if !res {
+        let res = loop {
+            match self.read_header() {
+                Ok(Some(header)) if !self.matches_filter(&header) => self.skip_body()?,
+                res => break res,
+            }
+        };
+
+        if !matches!(res, Ok(Some(_))) {
             self.sync_and_set_cur_pos();
         }
 
         res
     }
 
-    pub fn go_to_next_part(&mut self) -> bool {
+    // Reads the next message with its body, None if there are no new messages. Records moved
+    // to the dead letter queue are skipped
+    pub fn next_message(&mut self) -> Result<Option<Message>> {
+        loop {
+            let header = match self.pop_header()? {
+                Some(h) => h,
+                None => return Ok(None),
+            };
+
+            let part_id = self.id;
+            let mut data = vec![0; header.msg_length as usize];
+            if let Err(e) = self.pop_body(&mut data) {
+                if e.kind() == ErrorQueue::InvalidChecksum && self.state == ConsumerState::Ready {
+                    continue;
+                }
+                return Err(e);
+            }
+
+            let message = Message::new(part_id, header, data);
+            #[cfg(feature = "schema")]
+            let message = Message {
+                schema_id: self.schema_ids.get(&self.queue, part_id, message.seq),
+                ..message
+            };
+            return Ok(Some(message));
+        }
+    }
+
+    // Returns the next message without moving the consumer, None if there are no new messages
+    pub fn peek(&self) -> Result<Option<Message>> {
+        Ok(self.peek_n(1)?.pop())
+    }
+
+    // Returns up to n next messages without moving the consumer. The records are read with a separate file handle,
+    // so the read position, count_popped and the committed position are not changed
+    pub fn peek_n(&self, n: usize) -> Result<Vec<Message>> {
+        if self.state != ConsumerState::Ready {
+            return Err(self.error(ErrorQueue::NotReady, "peek"));
+        }
+
+        let mut messages = Vec::new();
+        let (mut part_id, mut pos, mut count) = (self.id, self.pos_record, self.count_popped);
+
+        while messages.len() < n {
+            match self.queue.next_record_position(part_id, pos, count)? {
+                Some((next_part_id, next_pos, next_count)) => {
+                    let (header, data) = read_record_at(&self.queue, next_part_id, next_pos, next_count + 1)?;
+                    part_id = next_part_id;
+                    pos = next_pos + HEADER_SIZE as u64 + header.msg_length as u64;
+                    count = next_count + 1;
+                    if self.matches_filter(&header) {
+                        messages.push(Message::new(part_id, header, data));
+                    }
+                },
+                None => break,
+            }
+        }
+
+        #[cfg(feature = "schema")]
+        {
+            let mut schema_ids = SchemaIds::default();
+            for message in &mut messages {
+                message.schema_id = schema_ids.get(&self.queue, message.part_id, message.seq);
+            }
+        }
+
+        Ok(messages)
+    }
+
+    // Schema of the record whose header is read by pop_header, None if the record is not tagged
+    #[cfg(feature = "schema")]
+    pub fn schema_id(&mut self) -> Option<u32> {
+        self.schema_ids.get(&self.queue, self.id, self.header.count_pushed)
+    }
+
+    // Gives only the records matching the filter, other records are skipped and counted as read, so they are committed as usual
+    pub fn with_filter(mut self, filter: MessageFilter) -> Consumer {
+        self.set_filter(Some(filter));
+        self
+    }
+
+    pub fn set_filter(&mut self, filter: Option<MessageFilter>) {
+        self.filter = filter;
+    }
+
+    fn matches_filter(&self, header: &Header) -> bool {
+        self.filter.as_ref().is_none_or(|f| f.matches(header))
+    }
+
+    // Moves past the body of the current record without reading it
+    fn skip_body(&mut self) -> Result<()> {
+        self.pos_record += HEADER_SIZE as u64 + self.header.msg_length as u64;
+        self.count_popped += 1;
+
+        if !self.use_mmap {
+            if let Err(e) = self.queue.ff_queue.seek(SeekFrom::Start(self.pos_record)) {
+                return Err(self.error(ErrorQueue::FailRead, "skip body").with_source(e));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn messages(&mut self) -> Messages<'_> {
+        Messages::new(self)
+    }
+
+    pub fn go_to_next_part(&mut self) -> Result<bool> {
         if self.count_popped >= self.queue.count_pushed {
             if let Err(e) = self.queue.get_info_of_part(self.id, false) {
-                error!("{}, queue:consumer({}):pop, queue {}{} not ready", e.as_str(), self.name, self.queue.name, self.id);
-                return false;
+                error!("{}, queue:consumer({}):pop, queue {}{} not ready", e, self.name, self.queue.name, self.id);
+                return Err(e);
             }
         }
 
@@ -262,10 +592,10 @@ impl Consumer {
 
                     if let Err(e) = self.queue.get_info_of_part(self.id, false) {
                         if e == ErrorQueue::NotFound {
-                            warn!("queue:consumer({}):pop, queue {}:{} {}", self.name, self.queue.name, self.id, e.as_str());
+                            warn!("queue:consumer({}):pop, queue {}:{} {}", self.name, self.queue.name, self.id, e);
                         } else {
-                            error!("queue:consumer({}):pop, queue {}:{} {}", self.name, self.queue.name, self.id, e.as_str());
-                            return false;
+                            error!("queue:consumer({}):pop, queue {}:{} {}", self.name, self.queue.name, self.id, e);
+                            return Err(e);
                         }
                     } else {
                         warn!("use next part {}", self.id);
@@ -276,46 +606,54 @@ impl Consumer {
                 self.count_popped = 0;
                 self.pos_record = 0;
 
-                self.open(true);
-                self.commit();
+                self.open(true)?;
+                if self.mode == Mode::ReadWrite && self.commit_on_next_part {
+                    self.commit()?;
+                }
 
                 if let Err(e) = self.queue.open_part(self.id) {
-                    error!("queue:consumer({}):pop, queue {}:{}, open part: {}", self.name, self.queue.name, self.id, e.as_str());
+                    error!("queue:consumer({}):pop, queue {}:{}, open part: {}", self.name, self.queue.name, self.id, e);
+                    self.state = ConsumerState::Failed(e.kind());
+                    return Err(e);
                 }
-                return true;
+                return Ok(true);
             }
         }
-        false
+        Ok(false)
     }
 
     fn is_empty_part(&mut self) -> bool {
         self.queue.count_pushed == 0
     }
 
-    fn read_header(&mut self) -> bool {
-        if self.go_to_next_part() {
+    fn read_header(&mut self) -> Result<Option<Header>> {
+        if self.go_to_next_part()? {
             while self.is_empty_part() {
-                if !self.go_to_next_part() {
+                if !self.go_to_next_part()? {
                     break;
                 }
             }
         }
 
         let mut buf = vec![0; HEADER_SIZE];
-        match self.queue.ff_queue.read(&mut buf[..]) {
-            Ok(len) => {
-                //println!("@len={}, id={}", len, self.id);
-                if len < HEADER_SIZE {
-                    //self.is_ready = false;
-                    //error!("fail read message header: len={}", len);
-                    return false;
-                }
-            },
-            Err(_) => {
-                error!("[queue:consumer] fail read message header");
-                //self.is_ready = false;
-                return false;
-            },
+        if self.use_mmap {
+            match self.mapped(self.pos_record, HEADER_SIZE) {
+                Some(b) => buf.copy_from_slice(b),
+                None => return Ok(None),
+            }
+        } else {
+            match self.queue.ff_queue.read(&mut buf[..]) {
+                Ok(len) => {
+                    //println!("@len={}, id={}", len, self.id);
+                    if len < HEADER_SIZE {
+                        return Ok(None);
+                    }
+                },
+                Err(e) => {
+                    error!("[queue:consumer] fail read message header");
+                    return Err(self.error(ErrorQueue::FailRead, "pop header").with_source(e));
+                },
+            }
         }
 
         let header = Header::create_from_buf(&buf);
@@ -324,15 +662,17 @@ impl Consumer {
             if header.magic_marker != MAGIC_MARKER {
                 error!("[queue:consumer] header is invalid: not found magic marker");
                 self.seek_next_pos();
-            } else {
-                error!("[queue:consumer] header is invalid: record header count_pushed {} > queue count pushed {}", header.count_pushed, self.queue.count_pushed);
+                return Err(self.error(ErrorQueue::InvalidHeader, "pop header, not found magic marker"));
             }
-            return false;
+
+            // the record is written, but not published by the writer yet (an open transaction or a batch in progress)
+            debug!("[queue:consumer] record {} is not published yet, queue count pushed {}", header.count_pushed, self.queue.count_pushed);
+            return Ok(None);
         }
 
         if header.start_pos >= self.queue.right_edge {
             error!("[queue:consumer] header is invalid");
-            return false;
+            return Err(self.error(ErrorQueue::InvalidHeader, "pop header, start position out of the part"));
         }
 
         buf[21] = 0;
@@ -343,8 +683,12 @@ impl Consumer {
         self.hash = Hasher::new();
         self.hash.update(&buf[..]);
 
+        if let MsgType::Unknown(t) = header.msg_type {
+            warn!("[queue:consumer] ({}): record pos={} has unknown message type {}, written by a newer version", self.name, header.start_pos, t);
+        }
+
         self.header = header;
-        true
+        Ok(Some(self.header.clone()))
     }
 
     pub fn seek_next_pos(&mut self) -> bool {
@@ -370,7 +714,6 @@ impl Consumer {
                             }
                             warn!("[queue:consumer] next record pos={}, delta={}", new_pos, new_pos - self.header.start_pos);
                             self.pos_record = new_pos;
-                            self.is_ready = true;
                             self.count_popped += 1;
                             return true;
                         }
@@ -397,21 +740,25 @@ impl Consumer {
         }
     }
 
-    pub fn pop_body(&mut self, msg: &mut [u8]) -> Result<usize, ErrorQueue> {
-        if !self.is_ready {
-            return Err(ErrorQueue::NotReady);
+    pub fn pop_body(&mut self, msg: &mut [u8]) -> Result<usize> {
+        if self.state != ConsumerState::Ready {
+            return Err(self.error(ErrorQueue::NotReady, "pop body"));
+        }
+
+        if self.use_mmap {
+            let pos = self.pop_mapped_body(msg.len())?;
+            return match self.part_map.as_ref().and_then(|m| m.slice(pos, msg.len())) {
+                Some(body) => {
+                    msg.copy_from_slice(body);
+                    Ok(msg.len())
+                },
+                None => Err(self.error(ErrorQueue::FailRead, "pop body")),
+            };
         }
 
         if let Ok(readied_size) = self.queue.ff_queue.read(msg) {
             if readied_size != msg.len() {
-                if self.count_popped == self.queue.count_pushed {
-                    warn!("[queue:consumer] detected problem with 'Read Tail Message': size fail");
-
-                    if self.queue.ff_queue.seek(SeekFrom::Start(self.pos_record)).is_ok() {
-                        return Err(ErrorQueue::FailReadTailMessage);
-                    }
-                }
-                return Err(ErrorQueue::FailRead);
+                return Err(self.fail_read_body());
             }
 
             //debug!("msg={:?}", msg);
@@ -422,35 +769,405 @@ impl Consumer {
             let crc32: u32 = self.hash.clone().finalize();
 
             if crc32 != self.header.crc {
-                if self.count_popped == self.queue.count_pushed {
-                    warn!("[queue:consumer] detected problem with 'Read Tail Message': CRC fail");
-
-                    if self.queue.ff_queue.seek(SeekFrom::Start(self.pos_record)).is_ok() {
-                        return Err(ErrorQueue::FailReadTailMessage);
-                    }
-                }
-
-                error!("[queue:consumer] CRC fail, pos={}, record size={}", self.header.start_pos, self.header.msg_length + HEADER_SIZE as u32);
-                self.is_ready = false;
-                return Err(ErrorQueue::InvalidChecksum);
+                return Err(self.fail_checksum(msg));
             }
             self.count_popped += 1;
 
             Ok(readied_size)
         } else {
-            Err(ErrorQueue::FailRead)
+            Err(self.error(ErrorQueue::FailRead, "pop body"))
+        }
+    }
+
+    pub fn pop_body_ref(&mut self) -> Result<&[u8]> {
+        if self.state != ConsumerState::Ready || !self.use_mmap {
+            return Err(self.error(ErrorQueue::NotReady, "pop body ref"));
+        }
+
+        let len = self.header.msg_length as usize;
+        let pos = self.pop_mapped_body(len)?;
+
+        match self.part_map.as_ref().and_then(|m| m.slice(pos, len)) {
+            Some(body) => Ok(body),
+            None => Err(self.error(ErrorQueue::FailRead, "pop body ref")),
+        }
+    }
+
+    fn pop_mapped_body(&mut self, len: usize) -> Result<u64> {
+        let pos = self.pos_record + HEADER_SIZE as u64;
+
+        let mut hash = self.hash.clone();
+        let crc32 = match self.mapped(pos, len) {
+            Some(body) => {
+                hash.update(body);
+                hash.finalize()
+            },
+            None => return Err(self.fail_read_body()),
+        };
+
+        self.pos_record = pos + len as u64;
+
+        if crc32 != self.header.crc {
+            let body = self.mapped(pos, len).map(|b| b.to_vec()).unwrap_or_default();
+            return Err(self.fail_checksum(&body));
+        }
+        self.count_popped += 1;
+
+        Ok(pos)
+    }
+
+    fn fail_read_body(&mut self) -> Error {
+        if self.count_popped == self.queue.count_pushed {
+            warn!("[queue:consumer] detected problem with 'Read Tail Message': size fail");
+
+            if self.queue.ff_queue.seek(SeekFrom::Start(self.pos_record)).is_ok() {
+                return self.error(ErrorQueue::FailReadTailMessage, "pop body, size fail");
+            }
+        }
+        self.error(ErrorQueue::FailRead, "pop body, size fail")
+    }
+
+    fn fail_checksum(&mut self, msg: &[u8]) -> Error {
+        if self.count_popped == self.queue.count_pushed {
+            warn!("[queue:consumer] detected problem with 'Read Tail Message': CRC fail");
+
+            if self.queue.ff_queue.seek(SeekFrom::Start(self.pos_record)).is_ok() {
+                return self.error(ErrorQueue::FailReadTailMessage, "pop body, CRC fail");
+            }
+        }
+
+        error!("[queue:consumer] CRC fail, pos={}, record size={}", self.header.start_pos, self.header.msg_length + HEADER_SIZE as u32);
+
+        if self.use_dead_letter_queue {
+            match self.put_dead_letter(msg, REASON_INVALID_CHECKSUM) {
+                Ok(()) => {
+                    warn!("[queue:consumer] record pos={} moved to dead letter queue, skip it", self.header.start_pos);
+                    self.count_popped += 1;
+                    return self.error(ErrorQueue::InvalidChecksum, "pop body, CRC fail");
+                },
+                Err(e) => {
+                    error!("[queue:consumer] fail put record to dead letter queue, err={}", e);
+                },
+            }
+        }
+
+        self.state = ConsumerState::InvalidRecord;
+        self.error(ErrorQueue::InvalidChecksum, "pop body, CRC fail")
+    }
+
+    pub fn set_mmap(&mut self, enable: bool) {
+        if self.use_mmap && !enable {
+            self.part_map = None;
+            self.sync_and_set_cur_pos();
+        }
+        self.use_mmap = enable;
+    }
+
+    // Returns bytes of the current part from the mapping, the mapping follows right edge of the part
+    fn mapped(&mut self, pos: u64, len: usize) -> Option<&[u8]> {
+        if self.part_map.as_ref().is_none_or(|m| m.part_id != self.id) {
+            match PartMap::open(&self.queue, self.id) {
+                Ok(m) => self.part_map = Some(m),
+                Err(_) => return None,
+            }
+        }
+
+        let right_edge = self.queue.right_edge;
+        let part_map = self.part_map.as_mut()?;
+        if part_map.len() != right_edge && part_map.remap(right_edge).is_err() {
+            return None;
+        }
+
+        part_map.slice(pos, len)
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        if self.state != ConsumerState::Ready {
+            return Err(Error::new(ErrorQueue::NotReady, "commit").with_path(self.info_pop_file_name()));
+        }
+
+        // nothing is persisted for an ephemeral consumer
+        if self.ephemeral {
+            self.count_committed = self.count_popped;
+            self.last_commit = Instant::now();
+            return Ok(());
+        }
+
+        // a consumer opened for reading follows the checkpoint of the consumer with the same name and never replaces it
+        if self.mode == Mode::Read {
+            return Err(Error::new(ErrorQueue::NotReady, "commit, consumer is opened for reading").with_path(self.info_pop_file_name()));
+        }
+
+        let checkpoint = Checkpoint {
+            part_id: self.id,
+            pos: self.pos_record,
+            count_popped: self.count_popped,
+        };
+        if let Err(e) = write_checkpoint(&self.info_pop_file_name(), &self.queue.name, &self.name, &checkpoint) {
+            error!("[queue:consumer] fail put info, err={}", e);
+            self.state = ConsumerState::Failed(ErrorQueue::FailWrite);
+            return Err(e);
+        }
+
+        self.count_committed = self.count_popped;
+        self.last_commit = Instant::now();
+        Ok(())
+    }
+
+    pub fn set_commit_policy(&mut self, policy: CommitPolicy) {
+        self.commit_policy = policy;
+    }
+
+    pub fn commit_policy(&self) -> CommitPolicy {
+        self.commit_policy
+    }
+
+    fn count_uncommitted(&self) -> u32 {
+        self.count_popped.saturating_sub(self.count_committed)
+    }
+
+    fn commit_by_policy(&mut self) -> Result<()> {
+        if self.mode == Mode::Read || self.count_uncommitted() == 0 {
+            return Ok(());
+        }
+
+        let need_commit = match self.commit_policy {
+            CommitPolicy::Manual | CommitPolicy::OnDrop => false,
+            CommitPolicy::EveryMessage => true,
+            CommitPolicy::EveryN(n) => self.count_uncommitted() >= n,
+            CommitPolicy::Interval(interval) => self.last_commit.elapsed() >= interval,
+        };
+
+        if need_commit {
+            self.commit()?;
         }
+        Ok(())
     }
 
-    pub fn commit(&mut self) -> bool {
-        if self.ff_info_pop.seek(SeekFrom::Start(0)).is_err() {
-            error!("[queue:consumer] fail put info, set consumer.ready = false");
-            self.is_ready = false;
+    pub fn seek_to_start(&mut self) -> Result<()> {
+        match self.queue.part_ids()?.first() {
+            Some(first) => self.seek_to_part(*first),
+            None => Err(Error::new(ErrorQueue::NotFound, "seek to start, queue has no parts").with_path(&self.base_path)),
         }
-        if self.ff_info_pop.write(format!("{};{};{};{};{}\n", self.queue.name, self.name, self.pos_record, self.count_popped, self.id).as_bytes()).is_err() {
-            error!("[queue:consumer] fail put info, set consumer.ready = false");
-            self.is_ready = false;
+    }
+
+    pub fn seek_to_end(&mut self) -> Result<()> {
+        if !self.queue.get_info_queue() {
+            return Err(Error::new(ErrorQueue::NotReady, "seek to end, get info of queue").with_path(self.queue.info_queue_file_name()));
         }
-        self.is_ready
+
+        let last = self.queue.id;
+        self.seek_to(last, |q| Ok((q.right_edge, q.count_pushed)))
+    }
+
+    pub fn seek_to_part(&mut self, part_id: u32) -> Result<()> {
+        self.seek_to(part_id, |_| Ok((0, 0)))
     }
+
+    // Moves to the record with the sequence number seq (count_pushed of the record) in the current part,
+    // seq = count_pushed + 1 moves to the end of the part
+    pub fn seek_to_sequence(&mut self, seq: u32) -> Result<()> {
+        self.seek_to(self.id, |q| Ok((find_record(q, seq)?, seq - 1)))
+    }
+
+    // Moves to the first record written at or after the time, with the precision of the interval of the time index.
+    // Parts without the time index are skipped
+    pub fn seek_to_time(&mut self, time: SystemTime) -> Result<()> {
+        let ts = to_millis(time);
+
+        let mut parts = Vec::new();
+        for part_id in self.queue.part_ids()? {
+            match PartTimeIndex::read_bounds(&self.queue, part_id) {
+                Ok(Some((_, max_ts))) => parts.push((part_id, max_ts)),
+                Ok(None) => {},
+                Err(e) => debug!("[queue:consumer] ({}): part {} has no time index, err={}", self.name, part_id, e),
+            }
+        }
+
+        let idx = parts.partition_point(|(_, max_ts)| *max_ts < ts);
+        let part_id = match parts.get(idx) {
+            Some((part_id, _)) => *part_id,
+            None => return self.seek_to_end(),
+        };
+
+        let index = PartTimeIndex::read(&self.queue, part_id)?;
+        let entry = index.find(ts).copied();
+
+        self.seek_to(part_id, |q| match entry {
+            Some(e) if e.seq <= q.count_pushed => Ok((e.pos, e.seq - 1)),
+            _ => Ok((q.right_edge, q.count_pushed)),
+        })
+    }
+
+    // Opens the part, finds the position in it and persists it. On failure the consumer stays at its position
+    fn seek_to<F>(&mut self, part_id: u32, find: F) -> Result<()>
+    where
+        F: FnOnce(&mut Queue) -> Result<(u64, u32)>,
+    {
+        if !Path::new(&self.queue.part_file_name(part_id)).exists() {
+            return Err(Error::new(ErrorQueue::NotFound, "seek, part not found").with_part(part_id).with_path(self.queue.part_file_name(part_id)));
+        }
+
+        // the queue of the consumer is opened for reading, its latch can be reset
+        self.queue.is_ready = true;
+
+        let res = self.queue.open_part(part_id).and_then(|_| find(&mut self.queue)).and_then(|(pos, count)| {
+            check_record_boundary(&mut self.queue, pos, count)?;
+            Ok((pos, count))
+        });
+
+        let (pos, count) = match res {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("[queue:consumer] ({}): fail seek in part {}, err={}", self.name, part_id, e);
+                self.queue.is_ready = true;
+                if let Err(e) = self.queue.open_part(self.id) {
+                    self.state = ConsumerState::Failed(e.kind());
+                }
+                self.sync_and_set_cur_pos();
+                return Err(e);
+            },
+        };
+
+        info!("[queue:consumer] ({}): seek to part {}, position {}, count popped {}", self.name, part_id, pos, count);
+
+        self.id = part_id;
+        self.pos_record = pos;
+        self.count_popped = count;
+        self.state = ConsumerState::Ready;
+        self.sync_and_set_cur_pos();
+
+        if self.mode == Mode::ReadWrite {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    pub fn set_dead_letter_queue(&mut self, enable: bool) {
+        self.use_dead_letter_queue = enable;
+    }
+
+    pub fn reject(&mut self, msg: &[u8], reason: &str) -> Result<()> {
+        self.put_dead_letter(msg, reason)
+    }
+
+    fn put_dead_letter(&mut self, msg: &[u8], reason: &str) -> Result<()> {
+        if self.mode == Mode::Read {
+            return Err(Error::new(ErrorQueue::NotReady, "put dead letter, consumer is read only"));
+        }
+
+        if self.dead_letter_queue.is_none() {
+            match dead_letter_writer(&self.base_path, &self.queue.name) {
+                Ok(q) => self.dead_letter_queue = Some(q),
+                Err(e) => {
+                    error!("[queue:consumer] fail open dead letter queue of {}, err={}", self.queue.name, e);
+                    return Err(e);
+                },
+            }
+        }
+
+        let letter = DeadLetter {
+            queue_name: self.queue.name.to_owned(),
+            part_id: self.id,
+            pos: self.header.start_pos,
+            count_pushed: self.header.count_pushed,
+            msg_type: self.header.msg_type,
+            reason: reason.to_owned(),
+            data: msg.to_vec(),
+        };
+
+        if let Some(dlq) = &self.dead_letter_queue {
+            let mut dlq = dlq.lock().unwrap_or_else(|e| e.into_inner());
+            dlq.push(&letter.to_bytes(), MsgType::Object)?;
+        }
+
+        Ok(())
+    }
+
+    // Current position: part id, position and count of read records of the part
+    pub(crate) fn position(&self) -> (u32, u64, u32) {
+        (self.id, self.pos_record, self.count_popped)
+    }
+
+    // Persists the position of a consumer that reads the records of the queue by itself
+    pub(crate) fn commit_position(&mut self, part_id: u32, pos: u64, count: u32) -> Result<()> {
+        self.set_position(part_id, pos, count)?;
+        self.commit()
+    }
+
+    // Moves the consumer to a position returned by Consumer::position, the position is not persisted
+    pub(crate) fn set_position(&mut self, part_id: u32, pos: u64, count: u32) -> Result<()> {
+        if part_id != self.id {
+            self.queue.is_ready = true;
+            self.queue.open_part(part_id)?;
+            self.part_map = None;
+        }
+
+        self.id = part_id;
+        self.pos_record = pos;
+        self.count_popped = count;
+        self.sync_and_set_cur_pos();
+        Ok(())
+    }
+
+    pub(crate) fn info_pop_file_name(&self) -> String {
+        self.queue.base_path.to_owned() + "/" + &self.queue.name + "_info_pop_" + &self.name
+    }
+
+    // Error in the current record of the consumer
+    fn error(&self, kind: ErrorQueue, op: &'static str) -> Error {
+        Error::new(kind, op).with_part(self.id).with_path(self.queue.part_file_name(self.id))
+    }
+}
+
+// Reads the header of the record at the position of the current part of the queue
+fn read_header_at(queue: &mut Queue, pos: u64) -> Result<Header> {
+    let mut buf = [0; HEADER_SIZE];
+    if let Err(e) = queue.ff_queue.seek(SeekFrom::Start(pos)).and_then(|_| queue.ff_queue.read_exact(&mut buf)) {
+        return Err(Error::io(ErrorQueue::FailRead, "read header", queue.part_file_name(queue.id), e).with_part(queue.id));
+    }
+    Ok(Header::create_from_buf(&buf))
+}
+
+// Checks that the record with the sequence number count + 1 starts at the position of the current part,
+// the position after the last record is the end of the part
+pub(crate) fn check_record_boundary(queue: &mut Queue, pos: u64, count: u32) -> Result<()> {
+    if count == queue.count_pushed && pos == queue.right_edge {
+        return Ok(());
+    }
+
+    if count < queue.count_pushed && pos < queue.right_edge {
+        let header = read_header_at(queue, pos)?;
+        if header.magic_marker == MAGIC_MARKER && header.start_pos == pos && header.count_pushed == count + 1 {
+            return Ok(());
+        }
+    }
+
+    Err(Error::new(ErrorQueue::InvalidHeader, "seek, position is not a record boundary").with_part(queue.id).with_path(queue.part_file_name(queue.id)))
+}
+
+// Finds the start position of the record with the sequence number seq in the current part of the queue,
+// reading the headers from the nearest record of the offset index
+pub(crate) fn find_record(queue: &mut Queue, seq: u32) -> Result<u64> {
+    if seq == 0 || seq > queue.count_pushed + 1 {
+        return Err(Error::new(ErrorQueue::NotFound, "seek to sequence").with_part(queue.id));
+    }
+
+    let start = PartIndex::read(queue, queue.id).ok().and_then(|index| index.find(seq)).unwrap_or(IndexEntry {
+        seq: 1,
+        pos: 0,
+    });
+
+    let mut pos = start.pos;
+    for count in start.seq..seq {
+        let header = read_header_at(queue, pos)?;
+        if header.magic_marker != MAGIC_MARKER || header.count_pushed != count || header.start_pos != pos {
+            return Err(Error::new(ErrorQueue::InvalidHeader, "seek to sequence, invalid header")
+                .with_part(queue.id)
+                .with_path(queue.part_file_name(queue.id)));
+        }
+        pos += HEADER_SIZE as u64 + header.msg_length as u64;
+    }
+
+    Ok(pos)
 }
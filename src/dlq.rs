@@ -0,0 +1,80 @@
+use crate::error::Result;
+use crate::queue::Queue;
+use crate::record::*;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+
+pub const DLQ_SUFFIX: &str = "_dlq";
+
+pub const REASON_INVALID_CHECKSUM: &str = "invalid checksum";
+
+// Writers of the dead letter queues opened in the process by <base path>/<queue>_dlq
+static WRITERS: Mutex<BTreeMap<String, Weak<Mutex<Queue>>>> = Mutex::new(BTreeMap::new());
+
+// Returns the writer of the dead letter queue of the queue. The consumers of the queue in the process share one writer,
+// it is opened by the first of them and closed with the last one
+pub(crate) fn dead_letter_writer(base_path: &str, queue_name: &str) -> Result<Arc<Mutex<Queue>>> {
+    let dlq_name = queue_name.to_owned() + DLQ_SUFFIX;
+    let key = base_path.to_owned() + "/" + &dlq_name;
+
+    let mut writers = WRITERS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(writer) = writers.get(&key).and_then(Weak::upgrade) {
+        return Ok(writer);
+    }
+
+    let writer = Arc::new(Mutex::new(Queue::new(base_path, &dlq_name, Mode::ReadWrite)?));
+    writers.retain(|_, w| w.strong_count() > 0);
+    writers.insert(key, Arc::downgrade(&writer));
+    Ok(writer)
+}
+
+// Dead letter record: first line holds the origin of the message, then the original body as is
+// <queue name>;<part id>;<start pos>;<count pushed>;<msg type>;<reason>\n<body>,
+// the msg type is a letter for built-in types and a decimal number for others
+#[derive(Debug, PartialEq)]
+pub struct DeadLetter {
+    pub queue_name: String,
+    pub part_id: u32,
+    pub pos: u64,
+    pub count_pushed: u32,
+    pub msg_type: MsgType,
+    pub reason: String,
+    pub data: Vec<u8>,
+}
+
+impl DeadLetter {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let reason = self.reason.replace(['\n', '\r'], " ");
+        let t = self.msg_type.as_u8();
+        let msg_type = if t.is_ascii_alphabetic() { (t as char).to_string() } else { t.to_string() };
+        let mut buf = format!("{};{};{};{};{};{}\n", self.queue_name, self.part_id, self.pos, self.count_pushed, msg_type, reason).into_bytes();
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<DeadLetter> {
+        let eol = buf.iter().position(|b| *b == b'\n')?;
+        let line = std::str::from_utf8(&buf[..eol]).ok()?;
+
+        let mut fields = line.splitn(6, ';');
+        let queue_name = fields.next()?.to_owned();
+        let part_id = fields.next()?.parse::<u32>().ok()?;
+        let pos = fields.next()?.parse::<u64>().ok()?;
+        let count_pushed = fields.next()?.parse::<u32>().ok()?;
+        let msg_type = match fields.next()?.as_bytes() {
+            [t] if t.is_ascii_alphabetic() => MsgType::from(*t),
+            t => MsgType::from(std::str::from_utf8(t).ok()?.parse::<u8>().ok()?),
+        };
+        let reason = fields.next()?.to_owned();
+
+        Some(DeadLetter {
+            queue_name,
+            part_id,
+            pos,
+            count_pushed,
+            msg_type,
+            reason,
+            data: buf[eol + 1..].to_vec(),
+        })
+    }
+}
@@ -0,0 +1,106 @@
+use crate::record::ErrorQueue;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Error of a queue operation: the stable code (ErrorQueue) together with the context of the failure
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorQueue,
+    op: &'static str,
+    path: Option<PathBuf>,
+    part_id: Option<u32>,
+    source: Option<io::Error>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorQueue, op: &'static str) -> Error {
+        Error {
+            kind,
+            op,
+            path: None,
+            part_id: None,
+            source: None,
+        }
+    }
+
+    pub fn io<P: AsRef<Path>>(kind: ErrorQueue, op: &'static str, path: P, source: io::Error) -> Error {
+        Error::new(kind, op).with_path(path).with_source(source)
+    }
+
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> Error {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn with_part(mut self, part_id: u32) -> Error {
+        self.part_id = Some(part_id);
+        self
+    }
+
+    pub fn with_source(mut self, source: io::Error) -> Error {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn kind(&self) -> ErrorQueue {
+        self.kind
+    }
+
+    // Stable numeric code for FFI callers, see ErrorQueue
+    pub fn code(&self) -> i32 {
+        self.kind.code()
+    }
+
+    pub fn operation(&self) -> &'static str {
+        self.op
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn part_id(&self) -> Option<u32> {
+        self.part_id
+    }
+
+    pub fn io_error(&self) -> Option<&io::Error> {
+        self.source.as_ref()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.op, self.kind.as_str())?;
+        if let Some(id) = self.part_id {
+            write!(f, ", part {}", id)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, ", path {}", path.display())?;
+        }
+        if let Some(e) = &self.source {
+            write!(f, ": {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_ref().map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl From<Error> for ErrorQueue {
+    fn from(e: Error) -> Self {
+        e.kind
+    }
+}
+
+impl PartialEq<ErrorQueue> for Error {
+    fn eq(&self, other: &ErrorQueue) -> bool {
+        self.kind == *other
+    }
+}
@@ -0,0 +1,70 @@
+use crate::record::{Header, MsgType};
+use std::fmt;
+
+// Property of the record header checked by MessageFilter::Properties
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Property {
+    MsgType(MsgType),
+    // length of the body in bytes, inclusive
+    MinLength(u32),
+    MaxLength(u32),
+    // sequence number of the record in its part, inclusive
+    MinSequence(u32),
+    MaxSequence(u32),
+}
+
+impl Property {
+    pub fn matches(&self, header: &Header) -> bool {
+        match *self {
+            Property::MsgType(t) => header.msg_type == t,
+            Property::MinLength(len) => header.msg_length >= len,
+            Property::MaxLength(len) => header.msg_length <= len,
+            Property::MinSequence(seq) => header.count_pushed >= seq,
+            Property::MaxSequence(seq) => header.count_pushed <= seq,
+        }
+    }
+}
+
+// Selects the records given by the consumer by their header, the bodies of other records are not read
+pub enum MessageFilter {
+    // records of any of the types
+    Types(Vec<MsgType>),
+    // records matching all properties
+    Properties(Vec<Property>),
+    Predicate(Box<dyn Fn(&Header) -> bool + Send>),
+}
+
+impl MessageFilter {
+    pub fn types(types: &[MsgType]) -> MessageFilter {
+        MessageFilter::Types(types.to_vec())
+    }
+
+    pub fn properties(properties: &[Property]) -> MessageFilter {
+        MessageFilter::Properties(properties.to_vec())
+    }
+
+    pub fn predicate<F>(f: F) -> MessageFilter
+    where
+        F: Fn(&Header) -> bool + Send + 'static,
+    {
+        MessageFilter::Predicate(Box::new(f))
+    }
+
+    pub fn matches(&self, header: &Header) -> bool {
+        match self {
+            MessageFilter::Types(types) => types.contains(&header.msg_type),
+            MessageFilter::Properties(properties) => properties.iter().all(|p| p.matches(header)),
+            MessageFilter::Predicate(f) => f(header),
+        }
+    }
+}
+
+impl fmt::Debug for MessageFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageFilter::Types(types) => f.debug_tuple("Types").field(types).finish(),
+            MessageFilter::Properties(properties) => f.debug_tuple("Properties").field(properties).finish(),
+            MessageFilter::Predicate(_) => f.write_str("Predicate"),
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use crate::error::{Error, Result};
+use crate::index::read_record_at;
+use crate::message::Message;
+use crate::queue::Queue;
+use crate::record::*;
+use fs2::FileExt;
+use std::fs::*;
+use std::io::ErrorKind;
+
+// Record given to a member and not committed yet
+#[derive(Debug, PartialEq, Clone)]
+pub struct Claim {
+    pub member: String,
+    pub part_id: u32,
+    pub pos: u64,
+    pub seq: u32,
+}
+
+// Shared state of the group: the cursor of the group and the records in processing.
+// <queue>;<group>;<part id>;<pos>;<count popped>, then a line per claim <member>;<part id>;<pos>;<seq>
+#[derive(Debug, PartialEq, Clone)]
+pub struct GroupState {
+    pub part_id: u32,
+    pub pos: u64,
+    pub count_popped: u32,
+    pub claims: Vec<Claim>,
+}
+
+// Member of a consumer group, every message of the queue is given to one member of the group.
+// A member holds the lock of its lock file while it is alive, records of a member whose lock is released are given to other members
+pub struct GroupConsumer {
+    pub name: String,
+    pub member: String,
+    queue: Queue,
+    member_lock: File,
+    claim: Option<Claim>,
+}
+
+impl Drop for GroupConsumer {
+    fn drop(&mut self) {
+        let member_lock_name = self.member_lock_file_name(&self.member);
+        if let Err(e) = remove_file(&member_lock_name) {
+            error!("[queue:group] drop: {}:{}:{}, fail remove lock file {}, err={}", self.queue.name, self.name, self.member, member_lock_name, e);
+        }
+        if let Err(e) = FileExt::unlock(&self.member_lock) {
+            debug!("[queue:group] drop: {}:{}:{}, fail unlock, err={}", self.queue.name, self.name, self.member, e);
+        }
+    }
+}
+
+impl GroupConsumer {
+    pub fn new(base_path: &str, group_name: &str, member_name: &str, queue_name: &str) -> Result<GroupConsumer> {
+        let queue = Queue::new(base_path, queue_name, Mode::Read)?;
+
+        let member_lock_name = base_path.to_owned() + "/" + queue_name + "_group_" + group_name + "_member_" + member_name + ".lock";
+        let member_lock = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&member_lock_name) {
+            Ok(f) => f,
+            Err(e) => return Err(Error::io(ErrorQueue::FailOpen, "open group member lock", member_lock_name, e)),
+        };
+        if let Err(e) = member_lock.try_lock_exclusive() {
+            error!("[queue:group] {}:{}:{} attempt lock, err={}", queue_name, group_name, member_name, e);
+            return Err(Error::io(ErrorQueue::AlreadyOpen, "lock group member", member_lock_name, e));
+        }
+
+        let gc = GroupConsumer {
+            name: group_name.to_owned(),
+            member: member_name.to_owned(),
+            queue,
+            member_lock,
+            claim: None,
+        };
+
+        // a new group starts from the current part of the queue, as a new consumer
+        let _lock = gc.lock_group()?;
+        if gc.read_state()?.is_none() {
+            gc.write_state(&GroupState {
+                part_id: gc.queue.id,
+                pos: 0,
+                count_popped: 0,
+                claims: Vec::new(),
+            })?;
+        }
+        drop(_lock);
+
+        Ok(gc)
+    }
+
+    // Takes the next message of the group, the previous message of the member is committed.
+    // Messages of the members that are gone are taken first
+    pub fn next_message(&mut self) -> Result<Option<Message>> {
+        let _lock = self.lock_group()?;
+        let mut state = self.get_state()?;
+
+        if let Some(claim) = self.claim.take() {
+            state.claims.retain(|c| *c != claim);
+        }
+
+        let mut taken = None;
+        for c in state.claims.iter_mut() {
+            // own records are left by the previous run of the member
+            if c.member == self.member || !self.is_member_alive(&c.member) {
+                warn!("[queue:group] {}:{}:{} take record {}:{} of member {}", self.queue.name, self.name, self.member, c.part_id, c.seq, c.member);
+                c.member = self.member.to_owned();
+                taken = Some(c.clone());
+                break;
+            }
+        }
+
+        let claim = match taken {
+            Some(c) => c,
+            None => match self.queue.next_record_position(state.part_id, state.pos, state.count_popped)? {
+                Some((part_id, pos, count)) => {
+                    let claim = Claim {
+                        member: self.member.to_owned(),
+                        part_id,
+                        pos,
+                        seq: count + 1,
+                    };
+                    state.claims.push(claim.clone());
+                    claim
+                },
+                None => {
+                    self.write_state(&state)?;
+                    return Ok(None);
+                },
+            },
+        };
+
+        let (header, data) = read_record_at(&self.queue, claim.part_id, claim.pos, claim.seq)?;
+
+        if claim.part_id > state.part_id || (claim.part_id == state.part_id && claim.seq > state.count_popped) {
+            state.part_id = claim.part_id;
+            state.pos = claim.pos + HEADER_SIZE as u64 + header.msg_length as u64;
+            state.count_popped = claim.seq;
+        }
+        self.write_state(&state)?;
+
+        self.claim = Some(claim.clone());
+        Ok(Some(Message::new(claim.part_id, header, data)))
+    }
+
+    // Commits the message taken by the member
+    pub fn commit(&mut self) -> Result<()> {
+        let claim = match self.claim.take() {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+
+        let res = self.lock_group().and_then(|_lock| {
+            let mut state = self.get_state()?;
+            state.claims.retain(|c| *c != claim);
+            self.write_state(&state)
+        });
+
+        if res.is_err() {
+            self.claim = Some(claim);
+        }
+        res
+    }
+
+    pub fn group_state(&self) -> Result<GroupState> {
+        let _lock = self.lock_group()?;
+        self.get_state()
+    }
+
+    fn is_member_alive(&self, member: &str) -> bool {
+        match OpenOptions::new().read(true).write(true).open(self.member_lock_file_name(member)) {
+            // the lock is released when the file is closed
+            Ok(f) => f.try_lock_exclusive().is_err(),
+            Err(_) => false,
+        }
+    }
+
+    fn lock_group(&self) -> Result<File> {
+        let lock_name = self.state_file_name() + ".lock";
+
+        let file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&lock_name) {
+            Ok(f) => f,
+            Err(e) => return Err(Error::io(ErrorQueue::FailOpen, "open group lock", lock_name, e)),
+        };
+        if let Err(e) = file.lock_exclusive() {
+            return Err(Error::io(ErrorQueue::FailOpen, "lock group", lock_name, e));
+        }
+        Ok(file)
+    }
+
+    fn get_state(&self) -> Result<GroupState> {
+        match self.read_state()? {
+            Some(state) => Ok(state),
+            None => Err(Error::new(ErrorQueue::NotFound, "read group state").with_path(self.state_file_name())),
+        }
+    }
+
+    fn read_state(&self) -> Result<Option<GroupState>> {
+        let file_name = self.state_file_name();
+
+        let content = match read_to_string(&file_name) {
+            Ok(c) => c,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::io(ErrorQueue::FailRead, "read group state", file_name, e)),
+        };
+
+        let invalid = || Error::new(ErrorQueue::Other, "read group state, invalid format").with_path(&file_name);
+
+        let mut lines = content.lines();
+        let (queue_name, group_name, part_id, pos, count_popped) =
+            scan_fmt!(lines.next().unwrap_or_default(), "{};{};{};{};{}", String, String, u32, u64, u32).map_err(|_| invalid())?;
+        if queue_name != self.queue.name || group_name != self.name {
+            return Err(invalid());
+        }
+
+        let mut claims = Vec::new();
+        for line in lines {
+            let (member, part_id, pos, seq) = scan_fmt!(line, "{};{};{};{}", String, u32, u64, u32).map_err(|_| invalid())?;
+            claims.push(Claim {
+                member,
+                part_id,
+                pos,
+                seq,
+            });
+        }
+
+        Ok(Some(GroupState {
+            part_id,
+            pos,
+            count_popped,
+            claims,
+        }))
+    }
+
+    // The state file is replaced at once, a crash doesn't leave a partially written state
+    fn write_state(&self, state: &GroupState) -> Result<()> {
+        let file_name = self.state_file_name();
+        let tmp_file_name = file_name.to_owned() + ".tmp";
+
+        let mut content = format!("{};{};{};{};{}\n", self.queue.name, self.name, state.part_id, state.pos, state.count_popped);
+        for c in state.claims.iter() {
+            content += &format!("{};{};{};{}\n", c.member, c.part_id, c.pos, c.seq);
+        }
+
+        if let Err(e) = write(&tmp_file_name, content).and_then(|_| rename(&tmp_file_name, &file_name)) {
+            error!("[queue:group] {}:{}:{} fail write state, err={}", self.queue.name, self.name, self.member, e);
+            return Err(Error::io(ErrorQueue::FailWrite, "write group state", file_name, e));
+        }
+        Ok(())
+    }
+
+    fn state_file_name(&self) -> String {
+        self.queue.base_path.to_owned() + "/" + &self.queue.name + "_group_" + &self.name
+    }
+
+    fn member_lock_file_name(&self, member: &str) -> String {
+        self.state_file_name() + "_member_" + member + ".lock"
+    }
+}
@@ -0,0 +1,325 @@
+use crate::error::{Error, Result};
+use crate::queue::*;
+use crate::record::*;
+use std::fs::*;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+
+pub const INDEX_INTERVAL: u32 = 64;
+
+// <interval>, then entries <seq><pos> of the records 1, 1 + interval, 1 + 2 * interval, ..., native endian as in the record header
+const INDEX_HEADER_SIZE: u64 = 4;
+const INDEX_ENTRY_SIZE: u64 = 12;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct IndexEntry {
+    pub seq: u32,
+    pub pos: u64,
+}
+
+impl IndexEntry {
+    fn from_buf(buf: &[u8]) -> IndexEntry {
+        IndexEntry {
+            seq: u32::from_ne_bytes(buf[0..4].try_into().unwrap_or_default()),
+            pos: u64::from_ne_bytes(buf[4..12].try_into().unwrap_or_default()),
+        }
+    }
+
+    fn to_buf(self) -> [u8; INDEX_ENTRY_SIZE as usize] {
+        let mut buf = [0; INDEX_ENTRY_SIZE as usize];
+        buf[0..4].copy_from_slice(&self.seq.to_ne_bytes());
+        buf[4..12].copy_from_slice(&self.pos.to_ne_bytes());
+        buf
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PartIndex {
+    pub part_id: u32,
+    pub interval: u32,
+    pub entries: Vec<IndexEntry>,
+}
+
+impl PartIndex {
+    pub fn read(queue: &Queue, part_id: u32) -> Result<PartIndex> {
+        let file_name = queue.index_file_name(part_id);
+
+        let mut buf = Vec::new();
+        if let Err(e) = File::open(&file_name).and_then(|mut f| f.read_to_end(&mut buf)) {
+            return Err(Error::io(ErrorQueue::NotFound, "read index", file_name, e).with_part(part_id));
+        }
+
+        if buf.len() < INDEX_HEADER_SIZE as usize {
+            return Err(Error::new(ErrorQueue::Other, "read index, invalid format").with_path(file_name).with_part(part_id));
+        }
+
+        Ok(PartIndex {
+            part_id,
+            interval: u32::from_ne_bytes(buf[0..4].try_into().unwrap_or_default()).max(1),
+            entries: buf[INDEX_HEADER_SIZE as usize..].chunks_exact(INDEX_ENTRY_SIZE as usize).map(IndexEntry::from_buf).collect(),
+        })
+    }
+
+    // Builds the index of the published records of the part from its _queue file
+    pub fn build(queue: &Queue, part_id: u32, interval: u32) -> Result<PartIndex> {
+        let interval = interval.max(1);
+        let (right_edge, count_pushed) = queue.read_info_of_part(part_id)?;
+        let file_name = queue.part_file_name(part_id);
+
+        let file = match File::open(&file_name) {
+            Ok(f) => f,
+            Err(e) => return Err(Error::io(ErrorQueue::FailOpen, "build index", file_name, e).with_part(part_id)),
+        };
+        let mut reader = BufReader::new(file);
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        for seq in 1..=count_pushed {
+            if pos >= right_edge {
+                break;
+            }
+
+            let mut buf = [0; HEADER_SIZE];
+            if let Err(e) = reader.read_exact(&mut buf) {
+                return Err(Error::io(ErrorQueue::FailRead, "build index", file_name, e).with_part(part_id));
+            }
+
+            let header = Header::create_from_buf(&buf);
+            if header.magic_marker != MAGIC_MARKER || header.count_pushed != seq || header.start_pos != pos {
+                return Err(Error::new(ErrorQueue::InvalidHeader, "build index").with_path(file_name).with_part(part_id));
+            }
+
+            if (seq - 1).is_multiple_of(interval) {
+                entries.push(IndexEntry {
+                    seq,
+                    pos,
+                });
+            }
+
+            if let Err(e) = reader.seek_relative(header.msg_length as i64) {
+                return Err(Error::io(ErrorQueue::FailRead, "build index", file_name, e).with_part(part_id));
+            }
+            pos += HEADER_SIZE as u64 + header.msg_length as u64;
+        }
+
+        Ok(PartIndex {
+            part_id,
+            interval,
+            entries,
+        })
+    }
+
+    // Writes the index into the file of the part, the file is replaced at once
+    pub fn write(&self, queue: &Queue) -> Result<()> {
+        let file_name = queue.index_file_name(self.part_id);
+        let tmp_file_name = file_name.to_owned() + ".tmp";
+
+        let mut buf = Vec::with_capacity(INDEX_HEADER_SIZE as usize + self.entries.len() * INDEX_ENTRY_SIZE as usize);
+        buf.extend_from_slice(&self.interval.to_ne_bytes());
+        for entry in self.entries.iter() {
+            buf.extend_from_slice(&entry.to_buf());
+        }
+
+        if let Err(e) = write(&tmp_file_name, &buf).and_then(|_| rename(&tmp_file_name, &file_name)) {
+            return Err(Error::io(ErrorQueue::FailWrite, "write index", file_name, e).with_part(self.part_id));
+        }
+        Ok(())
+    }
+
+    // Returns the last entry at or before the record seq
+    pub fn find(&self, seq: u32) -> Option<IndexEntry> {
+        let idx = self.entries.partition_point(|e| e.seq <= seq);
+        if idx == 0 {
+            return None;
+        }
+        self.entries.get(idx - 1).copied()
+    }
+}
+
+// Offset index of the part being written
+pub(crate) struct IndexWriter {
+    file: File,
+    file_name: String,
+    interval: u32,
+    last_seq: u32,
+}
+
+impl IndexWriter {
+    // Opens the index of the part, the index is rebuilt if it doesn't match the published records of the part
+    pub(crate) fn open(queue: &Queue, part_id: u32, count_pushed: u32) -> Result<IndexWriter> {
+        let interval = match PartIndex::read(queue, part_id) {
+            Ok(index) => {
+                let expected = count_pushed.div_ceil(index.interval) as usize;
+                if index.entries.len() < expected {
+                    warn!("queue:{}:{} index is incomplete, rebuild it", queue.name, part_id);
+                    PartIndex::build(queue, part_id, index.interval)?.write(queue)?;
+                } else if index.entries.len() > expected {
+                    // entries of records that were not published
+                    PartIndex {
+                        part_id,
+                        interval: index.interval,
+                        entries: index.entries[..expected].to_vec(),
+                    }
+                    .write(queue)?;
+                }
+                index.interval
+            },
+            Err(_) => {
+                if count_pushed > 0 {
+                    warn!("queue:{}:{} index is not found, rebuild it", queue.name, part_id);
+                }
+                PartIndex::build(queue, part_id, INDEX_INTERVAL)?.write(queue)?;
+                INDEX_INTERVAL
+            },
+        };
+
+        let file_name = queue.index_file_name(part_id);
+        match OpenOptions::new().write(true).open(&file_name) {
+            Ok(file) => Ok(IndexWriter {
+                file,
+                file_name,
+                interval,
+                last_seq: count_pushed,
+            }),
+            Err(e) => Err(Error::io(ErrorQueue::FailOpen, "open index", file_name, e).with_part(part_id)),
+        }
+    }
+
+    pub(crate) fn add(&mut self, seq: u32, pos: u64) -> Result<()> {
+        // a record is written again at the same place if the previous one was not published
+        if seq <= self.last_seq {
+            return Ok(());
+        }
+        self.last_seq = seq;
+
+        if !(seq - 1).is_multiple_of(self.interval) {
+            return Ok(());
+        }
+
+        let entry = IndexEntry {
+            seq,
+            pos,
+        };
+
+        if let Err(e) = self.file.seek(SeekFrom::End(0)).and_then(|_| self.file.write_all(&entry.to_buf())) {
+            return Err(Error::io(ErrorQueue::FailWrite, "add index entry", &self.file_name, e));
+        }
+        Ok(())
+    }
+}
+
+// Reads the record seq of the part using the index, the index is built in memory if its file is missing
+pub(crate) fn read_record(queue: &Queue, part_id: u32, seq: u32) -> Result<(Header, Vec<u8>)> {
+    let (right_edge, count_pushed) = queue.read_info_of_part(part_id)?;
+    if seq == 0 || seq > count_pushed {
+        return Err(Error::new(ErrorQueue::NotFound, "get record").with_part(part_id));
+    }
+
+    let index = match PartIndex::read(queue, part_id) {
+        Ok(index) => index,
+        Err(e) => {
+            warn!("queue:{}:{} {}, build index in memory", queue.name, part_id, e);
+            PartIndex::build(queue, part_id, INDEX_INTERVAL)?
+        },
+    };
+    let start = index.find(seq).unwrap_or(IndexEntry {
+        seq: 1,
+        pos: 0,
+    });
+
+    read_record_from(queue, part_id, start, seq, right_edge)
+}
+
+// Reads the record seq of the part that starts at the position
+pub(crate) fn read_record_at(queue: &Queue, part_id: u32, pos: u64, seq: u32) -> Result<(Header, Vec<u8>)> {
+    let (right_edge, count_pushed) = queue.read_info_of_part(part_id)?;
+    if seq == 0 || seq > count_pushed {
+        return Err(Error::new(ErrorQueue::NotFound, "get record").with_part(part_id));
+    }
+
+    let start = IndexEntry {
+        seq,
+        pos,
+    };
+    read_record_from(queue, part_id, start, seq, right_edge)
+}
+
+// Reads the headers from the start record up to the record seq, checks the headers and the CRC of the record seq
+fn read_record_from(queue: &Queue, part_id: u32, start: IndexEntry, seq: u32, right_edge: u64) -> Result<(Header, Vec<u8>)> {
+    let file_name = queue.part_file_name(part_id);
+    let fail = |kind, e| Error::io(kind, "get record", &file_name, e).with_part(part_id);
+
+    let mut file = File::open(&file_name).map_err(|e| fail(ErrorQueue::FailOpen, e))?;
+
+    let mut pos = start.pos;
+    for s in start.seq..=seq {
+        let mut buf = [0; HEADER_SIZE];
+        file.seek(SeekFrom::Start(pos)).and_then(|_| file.read_exact(&mut buf)).map_err(|e| fail(ErrorQueue::FailRead, e))?;
+
+        let header = Header::create_from_buf(&buf);
+        if header.magic_marker != MAGIC_MARKER || header.count_pushed != s || header.start_pos != pos || pos >= right_edge {
+            return Err(Error::new(ErrorQueue::InvalidHeader, "get record").with_path(&file_name).with_part(part_id));
+        }
+
+        if s == seq {
+            let mut data = vec![0; header.msg_length as usize];
+            file.read_exact(&mut data).map_err(|e| fail(ErrorQueue::FailRead, e))?;
+
+            buf[21..25].fill(0);
+            let mut hash = crc32fast::Hasher::new();
+            hash.update(&buf);
+            hash.update(&data);
+            if hash.finalize() != header.crc {
+                return Err(Error::new(ErrorQueue::InvalidChecksum, "get record").with_path(&file_name).with_part(part_id));
+            }
+
+            return Ok((header, data));
+        }
+
+        pos += HEADER_SIZE as u64 + header.msg_length as u64;
+    }
+
+    Err(Error::new(ErrorQueue::NotFound, "get record").with_part(part_id))
+}
+
+// Finds the records of the part by reading them from the start, the scan stops at the first record
+// with an invalid header or checksum. Returns the right edge and the count of the records found
+pub(crate) fn scan_part(queue: &Queue, part_id: u32) -> Result<(u64, u32)> {
+    let file_name = queue.part_file_name(part_id);
+    let file = match File::open(&file_name) {
+        Ok(f) => f,
+        Err(e) => return Err(Error::io(ErrorQueue::FailOpen, "scan part", file_name, e).with_part(part_id)),
+    };
+    let mut reader = BufReader::new(file);
+
+    let (mut pos, mut count) = (0, 0);
+    loop {
+        let mut buf = [0; HEADER_SIZE];
+        if reader.read_exact(&mut buf).is_err() {
+            break;
+        }
+
+        let header = Header::create_from_buf(&buf);
+        if header.magic_marker != MAGIC_MARKER || header.count_pushed != count + 1 || header.start_pos != pos {
+            break;
+        }
+
+        let mut data = vec![0; header.msg_length as usize];
+        if reader.read_exact(&mut data).is_err() {
+            break;
+        }
+
+        buf[21..25].fill(0);
+        let mut hash = crc32fast::Hasher::new();
+        hash.update(&buf);
+        hash.update(&data);
+        if hash.finalize() != header.crc {
+            break;
+        }
+
+        pos += HEADER_SIZE as u64 + header.msg_length as u64;
+        count += 1;
+    }
+
+    Ok((pos, count))
+}
@@ -11,6 +11,24 @@ extern crate scan_fmt;
 #[macro_use]
 extern crate log;
 
+pub mod ack;
+#[cfg(feature = "async")]
+pub mod async_queue;
+pub mod bridge;
+pub mod checkpoint;
 pub mod consumer;
+pub mod dlq;
+pub mod error;
+pub mod filter;
+pub mod group;
+pub mod index;
+pub mod message;
+pub mod mmap;
+pub mod producer;
 pub mod queue;
 pub mod record;
+pub mod registry;
+#[cfg(feature = "schema")]
+pub mod schema;
+pub mod time_index;
+mod watch;
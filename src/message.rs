@@ -0,0 +1,132 @@
+use crate::consumer::Consumer;
+use crate::error::Result;
+use crate::record::*;
+
+// Message read by the consumer, owns its body
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub header: Header,
+    pub msg_type: MsgType,
+    // sequence number of the record in the part (count_pushed of the record)
+    pub seq: u32,
+    pub part_id: u32,
+    // start position of the record in the part
+    pub pos: u64,
+    pub data: Vec<u8>,
+    // schema the record is tagged by, filled by Consumer::next_message and Consumer::peek_n
+    #[cfg(feature = "schema")]
+    pub schema_id: Option<u32>,
+}
+
+// Identifies a record of the queue: the part and the sequence number of the record in the part
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone)]
+pub struct MessageId {
+    pub part_id: u32,
+    pub seq: u32,
+}
+
+impl Message {
+    pub(crate) fn new(part_id: u32, header: Header, data: Vec<u8>) -> Message {
+        Message {
+            msg_type: header.msg_type,
+            seq: header.count_pushed,
+            part_id,
+            pos: header.start_pos,
+            header,
+            data,
+            #[cfg(feature = "schema")]
+            schema_id: None,
+        }
+    }
+
+    pub fn id(&self) -> MessageId {
+        MessageId {
+            part_id: self.part_id,
+            seq: self.seq,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.data).ok()
+    }
+}
+
+// Iterator over the messages of the consumer, ends when there are no new messages or after the first error.
+// With auto commit the position of a message is committed when the next message is requested
+// or the iterator is dropped, that is after the caller has processed the message
+pub struct Messages<'a> {
+    consumer: &'a mut Consumer,
+    auto_commit: bool,
+    need_commit: bool,
+    done: bool,
+}
+
+impl<'a> Messages<'a> {
+    pub(crate) fn new(consumer: &'a mut Consumer) -> Messages<'a> {
+        Messages {
+            consumer,
+            auto_commit: false,
+            need_commit: false,
+            done: false,
+        }
+    }
+
+    pub fn auto_commit(mut self, enable: bool) -> Messages<'a> {
+        self.auto_commit = enable;
+        self
+    }
+
+    fn commit_processed(&mut self) -> Result<()> {
+        if self.need_commit {
+            self.need_commit = false;
+            self.consumer.commit()?;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Messages<'_> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        if let Err(e) = self.commit_processed() {
+            self.done = true;
+            return Some(Err(e));
+        }
+
+        match self.consumer.next_message() {
+            Ok(Some(msg)) => {
+                self.need_commit = self.auto_commit;
+                Some(Ok(msg))
+            },
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+impl Drop for Messages<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit_processed() {
+            error!("[queue:consumer] ({}): fail commit on drop of messages iterator, err={}", self.consumer.name, e);
+        }
+    }
+}
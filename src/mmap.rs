@@ -0,0 +1,83 @@
+use crate::error::{Error, Result};
+use crate::queue::Queue;
+use crate::record::*;
+use memmap2::{Mmap, MmapOptions};
+use std::fs::*;
+
+pub struct PartMap {
+    pub part_id: u32,
+    file: File,
+    map: Option<Mmap>,
+}
+
+impl PartMap {
+    pub fn open(queue: &Queue, part_id: u32) -> Result<PartMap> {
+        let qpp = queue.part_file_name(part_id);
+
+        match OpenOptions::new().read(true).open(&qpp) {
+            Ok(file) => Ok(PartMap {
+                part_id,
+                file,
+                map: None,
+            }),
+            Err(e) => {
+                error!("[queue:mmap] fail open part {}, err={}", qpp, e);
+                Err(Error::io(ErrorQueue::FailOpen, "open part for mapping", qpp, e).with_part(part_id))
+            },
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.map.as_ref().map_or(0, |m| m.len() as u64)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Maps the first len bytes of the part, the mapping is cut to the length of the file so it never reaches past its end
+    pub fn remap(&mut self, len: u64) -> Result<()> {
+        let file_len = match self.file.metadata() {
+            Ok(m) => m.len(),
+            Err(e) => {
+                error!("[queue:mmap] fail get length of part {}, err={}", self.part_id, e);
+                self.map = None;
+                return Err(Error::new(ErrorQueue::FailRead, "map part").with_part(self.part_id).with_source(e));
+            },
+        };
+        let len = len.min(file_len);
+
+        if len == self.len() {
+            return Ok(());
+        }
+
+        if len == 0 {
+            self.map = None;
+            return Ok(());
+        }
+
+        // The region [0, right_edge) is written before right_edge gets into the info file, and abort of a transaction
+        // truncates the part only past the published right edge, so the mapped bytes don't change.
+        // A part shorter than the mapping is mapped again with the shorter length
+        match unsafe { MmapOptions::new().len(len as usize).map(&self.file) } {
+            Ok(m) => {
+                self.map = Some(m);
+                Ok(())
+            },
+            Err(e) => {
+                error!("[queue:mmap] fail map part {}, len={}, err={}", self.part_id, len, e);
+                self.map = None;
+                Err(Error::new(ErrorQueue::FailRead, "map part").with_part(self.part_id).with_source(e))
+            },
+        }
+    }
+
+    pub fn slice(&self, pos: u64, len: usize) -> Option<&[u8]> {
+        let m = self.map.as_ref()?;
+        let end = pos.checked_add(len as u64)?;
+        if end > m.len() as u64 {
+            return None;
+        }
+        Some(&m[pos as usize..end as usize])
+    }
+}
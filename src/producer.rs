@@ -0,0 +1,104 @@
+use crate::error::{Error, Result};
+use crate::queue::Queue;
+use crate::record::*;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread;
+
+const MAX_BATCH_SIZE: usize = 1024;
+
+struct PushRequest {
+    data: Vec<u8>,
+    msg_type: MsgType,
+    done: SyncSender<Result<u64>>,
+}
+
+// Cloneable handle of the queue writer, pushes of all handles are written by one thread
+#[derive(Clone)]
+pub struct QueueProducer {
+    tx: Sender<PushRequest>,
+}
+
+pub struct PushHandle {
+    rx: Receiver<Result<u64>>,
+}
+
+impl PushHandle {
+    // Waits until the message is written and published, returns the right edge of the part after the message
+    pub fn wait(self) -> Result<u64> {
+        self.rx.recv().unwrap_or(Err(Error::new(ErrorQueue::NotReady, "push, writer thread is stopped")))
+    }
+}
+
+impl QueueProducer {
+    pub fn new(queue: Queue) -> Result<QueueProducer> {
+        let (tx, rx) = channel();
+        let name = queue.name.to_owned();
+
+        if let Err(e) = thread::Builder::new().name(format!("v_queue-writer-{}", name)).spawn(move || write_batches(queue, rx)) {
+            error!("queue:{} fail start writer thread, err={}", name, e);
+            return Err(Error::new(ErrorQueue::Other, "start writer thread").with_source(e));
+        }
+
+        Ok(QueueProducer {
+            tx,
+        })
+    }
+
+    pub fn push(&self, data: &[u8], msg_type: MsgType) -> PushHandle {
+        let (done, rx) = sync_channel(1);
+
+        let req = PushRequest {
+            data: data.to_vec(),
+            msg_type,
+            done,
+        };
+
+        if let Err(e) = self.tx.send(req) {
+            let _ = e.0.done.send(Err(Error::new(ErrorQueue::NotReady, "push, writer thread is stopped")));
+        }
+
+        PushHandle {
+            rx,
+        }
+    }
+}
+
+fn write_batches(mut queue: Queue, rx: Receiver<PushRequest>) {
+    let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+
+    while let Ok(req) = rx.recv() {
+        batch.push(req);
+        while batch.len() < MAX_BATCH_SIZE {
+            match rx.try_recv() {
+                Ok(req) => batch.push(req),
+                Err(_) => break,
+            }
+        }
+
+        let (right_edge, count_pushed) = (queue.right_edge, queue.count_pushed);
+        let mut results = Vec::with_capacity(batch.len());
+        for req in batch.iter() {
+            results.push(queue.write_message(&req.data, req.msg_type).map(|_| queue.right_edge));
+        }
+
+        // one update of the info file for the whole batch
+        let published = queue.put_info_push();
+
+        // the records of the batch are reported as failed, they must not be published with the next batch
+        if published.is_err() {
+            if let Err(e) = queue.truncate_part(right_edge, count_pushed) {
+                error!("queue:{}:{} fail drop unpublished batch, err={}", queue.name, queue.id, e);
+            }
+        }
+
+        for (req, res) in batch.drain(..).zip(results) {
+            let res = match (&published, res) {
+                (Err(e), Ok(_)) => Err(Error::new(e.kind(), e.operation()).with_part(queue.id)),
+                (_, res) => res,
+            };
+            let _ = req.done.send(res);
+        }
+    }
+
+    debug!("queue:{}:{} writer thread is stopped", queue.name, queue.id);
+}
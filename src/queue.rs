@@ -1,11 +1,29 @@
+use crate::checkpoint::replace_file;
+use crate::error::{Error, Result};
 use crate::fs2::FileExt;
+use crate::index::*;
+use crate::message::Message;
 use crate::record::*;
+#[cfg(feature = "schema")]
+use crate::schema::*;
+use crate::time_index::*;
 use crc32fast::Hasher;
 use std::fs::*;
 use std::io::prelude::*;
 use std::io::SeekFrom;
 use std::io::{BufRead, BufReader};
 use std::path::*;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const INFO_READ_ATTEMPTS: u32 = 5;
+const INFO_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+// Published state of the part when the transaction is started
+struct Transaction {
+    right_edge: u64,
+    count_pushed: u32,
+}
 
 pub struct Queue {
     pub base_path: String,
@@ -18,23 +36,34 @@ pub struct Queue {
     pub(crate) right_edge: u64,
     pub count_pushed: u32,
     pub id: u32,
+    time_index: Option<TimeIndexWriter>,
+    time_index_interval: Duration,
+    index: Option<IndexWriter>,
+    // records pushed in the transaction are written after the published ones and published together by commit
+    tx: Option<Transaction>,
+    // _info_queue is written and not synced yet, it is synced by the next commit of a transaction
+    info_queue_unsynced: bool,
+    // the lock of <queue>_queue.lock is held by the writer while it is alive
+    lock_file: Option<File>,
+    #[cfg(feature = "schema")]
+    schema: SchemaState,
 }
 
 impl Queue {
-    pub fn new(base_path: &str, queue_name: &str, in_mode: Mode) -> Result<Queue, ErrorQueue> {
+    pub fn new(base_path: &str, queue_name: &str, in_mode: Mode) -> Result<Queue> {
         if !Path::new(&base_path).exists() {
             if let Err(e) = create_dir_all(base_path) {
                 error!("queue:{} create path, err={}", queue_name, e);
-                return Err(ErrorQueue::FailWrite);
+                return Err(Error::io(ErrorQueue::FailWrite, "create queue path", base_path, e));
             }
         }
 
         let file_name_info_queue = base_path.to_owned() + "/" + queue_name + "_info_queue";
 
         let wfqi = if in_mode == Mode::ReadWrite {
-            OpenOptions::new().read(true).write(true).create(true).open(file_name_info_queue)
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&file_name_info_queue)
         } else {
-            OpenOptions::new().read(true).open(file_name_info_queue)
+            OpenOptions::new().read(true).open(&file_name_info_queue)
         };
 
         if let Ok(fqi) = wfqi {
@@ -52,72 +81,302 @@ impl Queue {
                 ff_info_queue: tmp_f1,
                 ff_info_push: tmp_f2,
                 id: 0,
+                time_index: None,
+                time_index_interval: TIME_INDEX_INTERVAL,
+                index: None,
+                tx: None,
+                info_queue_unsynced: false,
+                lock_file: None,
+                #[cfg(feature = "schema")]
+                schema: SchemaState::default(),
             };
 
-            let info_is_ok = queue.get_info_queue();
-
+            // only one writer of the queue, the info files are not changed before the lock is taken
             if in_mode == Mode::ReadWrite {
                 let file_name_lock = queue.base_path.to_owned() + "/" + queue_name + "_queue.lock";
 
-                match OpenOptions::new().read(true).write(true).create(true).open(file_name_lock) {
+                match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&file_name_lock) {
                     Ok(file) => {
-                        if let Err(e) = file.lock_exclusive() {
+                        if let Err(e) = file.try_lock_exclusive() {
                             error!("queue:{}:{} attempt lock, err={}", queue.name, queue.id, e);
-                            return Err(ErrorQueue::AlreadyOpen);
+                            return Err(Error::io(ErrorQueue::AlreadyOpen, "lock queue", file_name_lock, e));
                         }
+                        queue.lock_file = Some(file);
                     },
                     Err(e) => {
                         error!("queue:{}:{} prepare lock, err={}", queue.name, queue.id, e);
-                        return Err(ErrorQueue::FailOpen);
+                        return Err(Error::io(ErrorQueue::FailOpen, "open queue lock", file_name_lock, e));
                     },
                 }
+            }
+
+            let info_is_ok = match queue.read_info_queue() {
+                Ok(()) => true,
+                Err(e) if in_mode == Mode::ReadWrite && e.kind() != ErrorQueue::NotFound => queue.recover_info_queue(e),
+                Err(_) => false,
+            };
 
+            if in_mode == Mode::ReadWrite {
                 if info_is_ok {
                     queue.id += 1;
                     queue.count_pushed = 0;
                     queue.right_edge = 0;
                 }
 
+                queue.recover_info_of_closed_parts();
+
                 let part_name = queue.name.to_owned() + "-" + &queue.id.to_string();
 
                 if !Path::new(&part_name).exists() {
-                    if let Err(e) = create_dir_all(queue.base_path.to_owned() + "/" + &part_name) {
+                    let part_path = queue.base_path.to_owned() + "/" + &part_name;
+                    if let Err(e) = create_dir_all(&part_path) {
                         error!("queue:{}:{} create path, err={}", queue.name, queue.id, e);
-                        return Err(ErrorQueue::FailWrite);
+                        return Err(Error::io(ErrorQueue::FailWrite, "create part path", part_path, e).with_part(queue.id));
                     }
                 }
 
                 if let Err(e) = queue.open_part(queue.id) {
-                    error!("queue:{}:{} open part, err={:?}", queue.name, queue.id, e);
-                    return Err(ErrorQueue::FailOpen);
+                    error!("queue:{}:{} open part, err={}", queue.name, queue.id, e);
+                    return Err(e);
                 }
 
                 if let Err(e) = queue.put_info_push() {
-                    error!("queue:{}:{} open, write info of part, err={:?}", queue.name, queue.id, e);
-                    return Err(ErrorQueue::FailWrite);
+                    error!("queue:{}:{} open, write info of part, err={}", queue.name, queue.id, e);
+                    return Err(e);
                 }
 
                 if let Err(e) = queue.put_info_queue() {
-                    error!("queue:{}:{} open, write info of queue, err={:?}", queue.name, queue.id, e);
-                    return Err(ErrorQueue::FailWrite);
+                    error!("queue:{}:{} open, write info of queue, err={}", queue.name, queue.id, e);
+                    return Err(e);
                 }
             }
 
             if info_is_ok {
                 if let Err(e) = queue.get_info_of_part(queue.id, true) {
-                    error!("queue:{}:{} open, get info of part: {}", queue.name, queue.id, e.as_str());
+                    error!("queue:{}:{} open, get info of part: {}", queue.name, queue.id, e);
                 }
             }
 
             return Ok(queue);
         }
 
-        Err(ErrorQueue::NotReady)
+        match wfqi {
+            Err(e) => Err(Error::io(ErrorQueue::NotReady, "open queue info", file_name_info_queue, e)),
+            Ok(_) => Err(Error::new(ErrorQueue::NotReady, "open queue info")),
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8], in_msg_type: MsgType) -> Result<u64> {
+        let record_size = self.write_message(data, in_msg_type)?;
+
+        if self.tx.is_some() {
+            return Ok(self.right_edge);
+        }
+
+        if self.put_info_push().is_err() {
+            self.right_edge -= record_size;
+            self.count_pushed -= 1;
+        }
+
+        Ok(self.right_edge)
+    }
+
+    // Pushes the record tagged by the schema, in the strict mode the payload is validated against the schema
+    #[cfg(feature = "schema")]
+    pub fn push_with_schema(&mut self, data: &[u8], in_msg_type: MsgType, schema_id: u32) -> Result<u64> {
+        let prev = self.schema.schema_id.replace(schema_id);
+        let res = self.push(data, in_msg_type);
+        self.schema.schema_id = prev;
+        res
+    }
+
+    #[cfg(feature = "schema")]
+    pub fn set_schema_registry(&mut self, registry: SchemaRegistry) {
+        self.schema.registry = Some(registry);
+    }
+
+    #[cfg(feature = "schema")]
+    pub fn schema_registry(&mut self) -> Option<&mut SchemaRegistry> {
+        self.schema.registry.as_mut()
+    }
+
+    // Schema of the records pushed by push, the schema registered by another process is found after reloading the registry
+    #[cfg(feature = "schema")]
+    pub fn set_schema(&mut self, schema_id: Option<u32>) -> Result<()> {
+        if let Some(id) = schema_id {
+            let registry = match &mut self.schema.registry {
+                Some(r) => r,
+                None => return Err(Error::new(ErrorQueue::NotReady, "set schema, no schema registry")),
+            };
+            if registry.get(id).is_none() {
+                registry.reload()?;
+            }
+            if registry.get(id).is_none() {
+                return Err(Error::new(ErrorQueue::NotFound, "set schema, schema not found"));
+            }
+        }
+        self.schema.schema_id = schema_id;
+        Ok(())
+    }
+
+    // In the strict mode every pushed record must have a schema and match it
+    #[cfg(feature = "schema")]
+    pub fn set_strict_schema(&mut self, strict: bool) {
+        self.schema.strict = strict;
     }
 
-    pub fn push(&mut self, data: &[u8], in_msg_type: MsgType) -> Result<u64, ErrorQueue> {
-        if !self.is_ready || self.mode == Mode::Read || data.len() > std::u32::MAX as usize / 2 {
-            return Err(ErrorQueue::NotReady);
+    #[cfg(feature = "schema")]
+    fn validate_schema(&mut self, data: &[u8]) -> Result<()> {
+        if !self.schema.strict {
+            return Ok(());
+        }
+
+        let (registry, id) = match (&mut self.schema.registry, self.schema.schema_id) {
+            (Some(r), Some(id)) => (r, id),
+            _ => return Err(Error::new(ErrorQueue::InvalidSchema, "push, schema is not set").with_part(self.id)),
+        };
+        if registry.get(id).is_none() {
+            registry.reload()?;
+        }
+        registry.validate(id, data).map_err(|e| e.with_part(self.id))
+    }
+
+    // Writes the schema id of the last written record
+    #[cfg(feature = "schema")]
+    fn tag_schema(&mut self) -> Result<()> {
+        let schema_id = match self.schema.schema_id {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        if self.schema.ids.is_none() {
+            self.schema.ids = Some(SchemaIdWriter::open(self, self.id, self.count_pushed)?);
+        }
+        match &mut self.schema.ids {
+            Some(ids) => ids.add(self.count_pushed, schema_id),
+            None => Ok(()),
+        }
+    }
+
+    // Starts a transaction: the records pushed until commit are not visible to consumers,
+    // the records of a transaction that is aborted or not committed before a crash of the writer are never read
+    pub fn begin(&mut self) -> Result<()> {
+        if !self.is_ready || self.mode == Mode::Read {
+            return Err(Error::new(ErrorQueue::NotReady, "begin transaction").with_part(self.id));
+        }
+        if self.tx.is_some() {
+            return Err(Error::new(ErrorQueue::AlreadyOpen, "begin transaction, transaction is already started").with_part(self.id));
+        }
+
+        self.tx = Some(Transaction {
+            right_edge: self.right_edge,
+            count_pushed: self.count_pushed,
+        });
+        Ok(())
+    }
+
+    // Publishes the records of the transaction at once
+    pub fn commit(&mut self) -> Result<()> {
+        if self.tx.is_none() {
+            return Err(Error::new(ErrorQueue::NotReady, "commit transaction, transaction is not started").with_part(self.id));
+        }
+
+        // the records must be on disk before the info of the part refers to them
+        if let Err(e) = self.ff_queue.sync_data() {
+            return Err(Error::io(ErrorQueue::FailWrite, "commit transaction", self.part_file_name(self.id), e).with_part(self.id));
+        }
+        self.put_info_push()?;
+        self.tx = None;
+
+        // the records are published, they must stay published after a power loss once commit returns
+        if let Err(e) = self.ff_info_push.sync_data() {
+            return Err(Error::io(ErrorQueue::FailWrite, "commit transaction, sync info push", self.info_push_file_name(self.id), e).with_part(self.id));
+        }
+        if self.info_queue_unsynced {
+            if let Err(e) = self.ff_info_queue.sync_data() {
+                return Err(Error::io(ErrorQueue::FailWrite, "commit transaction, sync info queue", self.info_queue_file_name(), e));
+            }
+            self.info_queue_unsynced = false;
+        }
+        Ok(())
+    }
+
+    // Drops the records of the transaction, the next records are written in their place
+    pub fn abort(&mut self) -> Result<()> {
+        let tx = match self.tx.take() {
+            Some(tx) => tx,
+            None => return Err(Error::new(ErrorQueue::NotReady, "abort transaction, transaction is not started").with_part(self.id)),
+        };
+
+        debug!("queue:{}:{} abort transaction, drop {} records", self.name, self.id, self.count_pushed - tx.count_pushed);
+        self.truncate_part(tx.right_edge, tx.count_pushed)
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    // Drops the records written after the right edge, the next records are written in their place
+    pub(crate) fn truncate_part(&mut self, right_edge: u64, count_pushed: u32) -> Result<()> {
+        self.right_edge = right_edge;
+        self.count_pushed = count_pushed;
+
+        if let Err(e) = self.ff_queue.set_len(right_edge).and_then(|_| self.ff_queue.seek(SeekFrom::Start(right_edge))) {
+            self.is_ready = false;
+            return Err(Error::io(ErrorQueue::FailWrite, "truncate part", self.part_file_name(self.id), e).with_part(self.id));
+        }
+
+        // the entries of the dropped records point to the records written in their place
+        if self.index.is_some() {
+            self.index = match IndexWriter::open(self, self.id, count_pushed) {
+                Ok(index) => Some(index),
+                Err(e) => {
+                    error!("queue:{}:{} fail open index, err={}", self.name, self.id, e);
+                    None
+                },
+            };
+        }
+        if let Some(ti) = &mut self.time_index {
+            if let Err(e) = ti.truncate(count_pushed) {
+                error!("queue:{}:{} fail write time index, err={}", self.name, self.id, e);
+                self.time_index = None;
+            }
+        }
+
+        #[cfg(feature = "schema")]
+        if self.schema.ids.is_some() {
+            self.schema.ids = Some(SchemaIdWriter::open(self, self.id, count_pushed + 1)?);
+        }
+        Ok(())
+    }
+
+    // Validates the record against its schema, writes it and tags it by the schema id without publishing it
+    // in the info file, returns size of the record
+    pub(crate) fn write_message(&mut self, data: &[u8], in_msg_type: MsgType) -> Result<u64> {
+        #[cfg(feature = "schema")]
+        self.validate_schema(data)?;
+
+        let record_size = self.write_record(data, in_msg_type)?;
+
+        // the record is not pushed without its schema id
+        #[cfg(feature = "schema")]
+        if let Err(e) = self.tag_schema() {
+            if let Err(te) = self.truncate_part(self.right_edge - record_size, self.count_pushed - 1) {
+                error!("queue:{}:{} push, fail drop untagged record, err={}", self.name, self.id, te);
+            }
+            return Err(e);
+        }
+
+        Ok(record_size)
+    }
+
+    fn write_record(&mut self, data: &[u8], in_msg_type: MsgType) -> Result<u64> {
+        if !self.is_ready || self.mode == Mode::Read || data.len() > u32::MAX as usize / 2 {
+            return Err(Error::new(ErrorQueue::NotReady, "push").with_part(self.id));
+        }
+
+        if !in_msg_type.is_valid() {
+            return Err(Error::new(ErrorQueue::UnknownMsgType, "push").with_part(self.id));
         }
 
         let header = Header {
@@ -145,51 +404,105 @@ impl Queue {
 
         if let Err(e) = self.ff_queue.write(&bheader) {
             error!("queue:{}:{} push, write header, err={}", self.name, self.id, e);
-            return Err(ErrorQueue::FailWrite);
+            return Err(Error::io(ErrorQueue::FailWrite, "push, write header", self.part_file_name(self.id), e).with_part(self.id));
         }
         if let Err(e) = self.ff_queue.write(data) {
             error!("queue:{}:{} push, write body, err={}", self.name, self.id, e);
-            return Err(ErrorQueue::FailWrite);
+            return Err(Error::io(ErrorQueue::FailWrite, "push, write body", self.part_file_name(self.id), e).with_part(self.id));
         }
 
-        self.right_edge = self.right_edge + bheader.len() as u64 + data.len() as u64;
+        self.add_to_index(header.start_pos, header.count_pushed);
+
+        let record_size = bheader.len() as u64 + data.len() as u64;
+        self.right_edge += record_size;
         self.count_pushed += 1;
 
-        if self.put_info_push().is_err() {
-            self.right_edge = self.right_edge - bheader.len() as u64 - data.len() as u64;
-            self.count_pushed -= 1;
+        Ok(record_size)
+    }
+
+    // The indexes are auxiliary, the queue is written without an index if it can't be written
+    fn add_to_index(&mut self, pos: u64, seq: u32) {
+        if let Some(index) = &mut self.index {
+            if let Err(e) = index.add(seq, pos) {
+                error!("queue:{}:{} fail write index, err={}", self.name, self.id, e);
+                self.index = None;
+            }
         }
 
-        Ok(self.right_edge)
+        if let Some(ti) = &mut self.time_index {
+            if let Err(e) = ti.add(to_millis(SystemTime::now()), pos, seq) {
+                error!("queue:{}:{} fail write time index, err={}", self.name, self.id, e);
+                self.time_index = None;
+            }
+        }
     }
 
-    fn put_info_push(&mut self) -> Result<(), ErrorQueue> {
-        if self.ff_info_push.seek(SeekFrom::Start(0)).is_ok() {
-        } else {
+    // Reads the message with the sequence number seq (count_pushed of the record) of the current part
+    pub fn get(&self, seq: u32) -> Result<Message> {
+        self.get_in_part(self.id, seq)
+    }
+
+    // Reads the message with the sequence number seq of the part, the position of the queue is not changed
+    pub fn get_in_part(&self, part_id: u32, seq: u32) -> Result<Message> {
+        let (header, data) = read_record(self, part_id, seq)?;
+        Ok(Message::new(part_id, header, data))
+    }
+
+    // Rebuilds the offset index of the part from its _queue file
+    pub fn rebuild_index(&mut self, part_id: u32) -> Result<()> {
+        if self.mode != Mode::ReadWrite {
+            return Err(Error::new(ErrorQueue::NotReady, "rebuild index, queue is read only").with_part(part_id));
+        }
+
+        let interval = PartIndex::read(self, part_id).map_or(INDEX_INTERVAL, |index| index.interval);
+        PartIndex::build(self, part_id, interval)?.write(self)?;
+
+        if part_id == self.id {
+            self.index = Some(IndexWriter::open(self, part_id, self.count_pushed)?);
+        }
+        Ok(())
+    }
+
+    // Interval of the time index for the parts opened later, and for the current part if it is empty
+    pub fn set_time_index_interval(&mut self, interval: Duration) {
+        self.time_index_interval = interval;
+        if let Some(ti) = &mut self.time_index {
+            if let Err(e) = ti.set_interval(interval) {
+                error!("queue:{}:{} fail write time index, err={}", self.name, self.id, e);
+                self.time_index = None;
+            }
+        }
+    }
+
+    pub(crate) fn put_info_push(&mut self) -> Result<()> {
+        if let Err(e) = self.ff_info_push.seek(SeekFrom::Start(0)) {
             error!("fail put info push, set queue.ready = false");
             self.is_ready = false;
-            return Err(ErrorQueue::FailWrite);
+            return Err(Error::io(ErrorQueue::FailWrite, "put info push", self.info_push_file_name(self.id), e).with_part(self.id));
         }
 
-        let p = format!("{};{};{};", self.name, self.right_edge, self.count_pushed);
-        let mut hash = Hasher::new();
-        hash.update(p.as_bytes());
-
-        if let Err(e) = self.ff_info_push.write(format!("{}{}\n", p, hash.finalize()).as_bytes()) {
+        let line = self.info_push_line(self.right_edge, self.count_pushed);
+        if let Err(e) = self.ff_info_push.write(line.as_bytes()) {
             error!("fail put info push, set queue.ready = false, err={}", e);
             self.is_ready = false;
-            return Err(ErrorQueue::FailWrite);
+            return Err(Error::io(ErrorQueue::FailWrite, "put info push", self.info_push_file_name(self.id), e).with_part(self.id));
         }
 
         Ok(())
     }
 
-    fn put_info_queue(&mut self) -> Result<(), ErrorQueue> {
-        if self.ff_info_queue.seek(SeekFrom::Start(0)).is_ok() {
-        } else {
+    fn info_push_line(&self, right_edge: u64, count_pushed: u32) -> String {
+        let p = format!("{};{};{};", self.name, right_edge, count_pushed);
+        let mut hash = Hasher::new();
+        hash.update(p.as_bytes());
+        format!("{}{}\n", p, hash.finalize())
+    }
+
+    fn put_info_queue(&mut self) -> Result<()> {
+        if let Err(e) = self.ff_info_queue.seek(SeekFrom::Start(0)) {
             error!("fail put info queue, set queue.ready = false");
             self.is_ready = false;
-            return Err(ErrorQueue::FailWrite);
+            return Err(Error::io(ErrorQueue::FailWrite, "put info queue", self.info_queue_file_name(), e));
         }
 
         let p = format!("{};{};", self.name, self.id);
@@ -199,17 +512,18 @@ impl Queue {
         if let Err(e) = self.ff_info_queue.write(format!("{}{}\n", p, hash.finalize()).as_bytes()) {
             error!("fail put info queue, set queue.ready = false, err={}", e);
             self.is_ready = false;
-            return Err(ErrorQueue::FailWrite);
+            return Err(Error::io(ErrorQueue::FailWrite, "put info queue", self.info_queue_file_name(), e));
         }
+        self.info_queue_unsynced = true;
 
         Ok(())
     }
 
-    fn open_info_push(&mut self, part_id: u32) -> Result<(), ErrorQueue> {
-        let ipp = self.base_path.to_owned() + "/" + &self.name + "-" + &part_id.to_string() + "/" + &self.name + "_info_push";
+    fn open_info_push(&mut self, part_id: u32) -> Result<()> {
+        let ipp = self.info_push_file_name(part_id);
 
         let ffiq = if self.mode == Mode::ReadWrite {
-            OpenOptions::new().read(true).write(true).create(true).open(&ipp)
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&ipp)
         } else {
             OpenOptions::new().read(true).open(&ipp)
         };
@@ -219,60 +533,89 @@ impl Queue {
             Err(e) => {
                 debug!("[{}] fail open info push, part {}, mode={:?}, err={:?} {}", self.name, part_id, self.mode, e, ipp);
                 self.is_ready = false;
-                return Err(ErrorQueue::FailOpen);
+                return Err(Error::io(ErrorQueue::FailOpen, "open info push", ipp, e).with_part(part_id));
             },
         }
 
         Ok(())
     }
 
-    pub fn open_part(&mut self, part_id: u32) -> Result<(), ErrorQueue> {
+    pub fn open_part(&mut self, part_id: u32) -> Result<()> {
         if !self.is_ready {
-            return Err(ErrorQueue::NotReady);
+            return Err(Error::new(ErrorQueue::NotReady, "open part").with_part(part_id));
         }
 
         self.open_info_push(part_id)?;
 
-        let qpp = self.base_path.to_owned() + "/" + &self.name + "-" + &part_id.to_string() + "/" + &self.name + "_queue";
+        let qpp = self.part_file_name(part_id);
         let ffq = if self.mode == Mode::ReadWrite {
-            OpenOptions::new().read(true).write(true).create(true).open(qpp)
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&qpp)
         } else {
-            OpenOptions::new().read(true).open(qpp)
+            OpenOptions::new().read(true).open(&qpp)
         };
 
-        if let Ok(f) = ffq {
-            self.ff_queue = f;
-        } else {
-            debug!("[{}] fail open part {}", self.name, part_id);
-            self.is_ready = false;
-            return Err(ErrorQueue::FailOpen);
+        match ffq {
+            Ok(f) => self.ff_queue = f,
+            Err(e) => {
+                debug!("[{}] fail open part {}", self.name, part_id);
+                self.is_ready = false;
+                return Err(Error::io(ErrorQueue::FailOpen, "open part", qpp, e).with_part(part_id));
+            },
         }
 
         self.id = part_id;
 
         debug!("[{}] open part {}", self.name, part_id);
 
-        self.get_info_of_part(self.id, false)
-    }
+        self.get_info_of_part(self.id, false)?;
 
-    pub fn get_info_queue(&mut self) -> bool {
-        let mut id = 0;
+        if self.mode == Mode::ReadWrite {
+            // the records are written after the published ones
+            if let Err(e) = self.ff_queue.seek(SeekFrom::Start(self.right_edge)) {
+                self.is_ready = false;
+                return Err(Error::io(ErrorQueue::FailOpen, "open part, seek to right edge", qpp, e).with_part(part_id));
+            }
+
+            self.index = match IndexWriter::open(self, part_id, self.count_pushed) {
+                Ok(index) => Some(index),
+                Err(e) => {
+                    error!("queue:{}:{} fail open index, err={}", self.name, self.id, e);
+                    None
+                },
+            };
 
-        if self.ff_info_queue.seek(SeekFrom::Start(0)).is_err() {
-            return false;
+            #[cfg(feature = "schema")]
+            {
+                self.schema.ids = None;
+            }
+
+            self.time_index = match TimeIndexWriter::open(self, part_id, self.time_index_interval) {
+                Ok(ti) => Some(ti),
+                Err(e) => {
+                    error!("queue:{}:{} fail open time index, err={}", self.name, self.id, e);
+                    None
+                },
+            };
         }
+        Ok(())
+    }
 
-        if let Some(Ok(ll)) = BufReader::new(&self.ff_info_queue).lines().next() {
-            if let Ok((queue_name, _id, _crc)) = scan_fmt!(&ll, "{};{};{}", String, u32, String) {
-                if queue_name != self.name {
-                    return false;
+    pub fn get_info_queue(&mut self) -> bool {
+        match self.read_info_queue() {
+            Ok(()) => true,
+            Err(e) => {
+                if e.kind() == ErrorQueue::InvalidInfoChecksum {
+                    error!("queue:{} {}", self.name, e);
                 }
-
-                id = _id;
-            }
-        } else {
-            return false;
+                false
+            },
         }
+    }
+
+    // Reads the id of the current part from _info_queue, a line with an invalid checksum is read again
+    // to skip a line being written by the writer
+    pub fn read_info_queue(&mut self) -> Result<()> {
+        let id = read_info_with_retry(|| self.parse_info_queue())?;
 
         self.id = id;
         self.right_edge = 0;
@@ -280,37 +623,104 @@ impl Queue {
 
         //info!("@ read info_queue: name={}, id={}", self.name, self.id);
 
-        true
+        Ok(())
     }
 
-    pub fn get_info_of_part(&mut self, part_id: u32, reopen: bool) -> Result<(), ErrorQueue> {
-        if self.id != part_id || reopen {
-            self.open_info_push(part_id)?;
+    fn parse_info_queue(&self) -> Result<u32> {
+        let mut ff_info_queue = &self.ff_info_queue;
+        if let Err(e) = ff_info_queue.seek(SeekFrom::Start(0)) {
+            return Err(Error::io(ErrorQueue::FailRead, "get info of queue", self.info_queue_file_name(), e));
         }
 
-        let mut right_edge = 0;
-        let mut count_pushed = 0;
+        match BufReader::new(ff_info_queue).lines().next() {
+            Some(Ok(ll)) => {
+                if !is_valid_info_line(&ll) {
+                    return Err(Error::new(ErrorQueue::InvalidInfoChecksum, "get info of queue").with_path(self.info_queue_file_name()));
+                }
 
-        if self.ff_info_push.seek(SeekFrom::Start(0)).is_err() {
-            return Err(ErrorQueue::FailRead);
+                match scan_fmt!(&ll, "{};{};{}", String, u32, String) {
+                    Ok((queue_name, id, _crc)) if queue_name == self.name => Ok(id),
+                    Ok(_) => Err(Error::new(ErrorQueue::Other, "get info of queue, queue name mismatch").with_path(self.info_queue_file_name())),
+                    Err(_) => Err(Error::new(ErrorQueue::Other, "get info of queue, invalid format").with_path(self.info_queue_file_name())),
+                }
+            },
+            Some(Err(e)) => Err(Error::io(ErrorQueue::FailRead, "get info of queue", self.info_queue_file_name(), e)),
+            None => Err(Error::new(ErrorQueue::NotFound, "get info of queue, empty").with_path(self.info_queue_file_name())),
         }
+    }
 
-        if let Some(line) = BufReader::new(&self.ff_info_push).lines().next() {
-            if let Ok(ll) = line {
-                if let Ok((queue_name, position, pushed, _crc)) = scan_fmt!(&ll, "{};{};{};{}", String, u64, u32, String) {
-                    if queue_name != self.name {
-                        return Err(ErrorQueue::Other);
-                    }
+    // The writer continues after the last part found in the queue directory
+    fn recover_info_queue(&mut self, e: Error) -> bool {
+        match self.part_ids().ok().and_then(|ids| ids.last().copied()) {
+            Some(id) => {
+                warn!("queue:{} {}, recover id of the current part {} from the parts", self.name, e, id);
+                self.id = id;
+                self.right_edge = 0;
+                self.count_pushed = 0;
+                true
+            },
+            None => {
+                error!("queue:{} {}, parts are not found", self.name, e);
+                false
+            },
+        }
+    }
 
-                    right_edge = position;
-                    count_pushed = pushed;
-                } else {
-                    return Err(ErrorQueue::Other);
-                }
-            } else {
-                return Err(ErrorQueue::Other);
+    // The writer restores the info of the parts before its part from their records,
+    // otherwise the consumers can't pass a part whose info was corrupted before a restart
+    fn recover_info_of_closed_parts(&self) {
+        let ids = match self.part_ids() {
+            Ok(ids) => ids,
+            Err(e) => {
+                warn!("queue:{} {}, info of closed parts is not checked", self.name, e);
+                return;
+            },
+        };
+
+        for part_id in ids.into_iter().filter(|id| *id < self.id) {
+            let file_name = self.info_push_file_name(part_id);
+            match File::open(&file_name).map(|f| self.parse_info_push(&f, part_id)) {
+                Ok(Err(e)) if e.kind() == ErrorQueue::InvalidInfoChecksum => {
+                    warn!("queue:{}:{} {}, recover info of part from its records", self.name, part_id, e);
+                },
+                _ => continue,
+            }
+
+            let res = scan_part(self, part_id).and_then(|(right_edge, count_pushed)| {
+                replace_file(&file_name, self.info_push_line(right_edge, count_pushed).as_bytes(), false)
+                    .map_err(|e| Error::io(ErrorQueue::FailWrite, "recover info of part", &file_name, e).with_part(part_id))
+            });
+            if let Err(e) = res {
+                error!("queue:{}:{} {}", self.name, part_id, e);
             }
         }
+    }
+
+    pub fn get_info_of_part(&mut self, part_id: u32, reopen: bool) -> Result<()> {
+        if self.id != part_id || reopen {
+            self.open_info_push(part_id)?;
+        }
+
+        let res = read_info_with_retry(|| {
+            let mut ff_info_push = &self.ff_info_push;
+            if let Err(e) = ff_info_push.seek(SeekFrom::Start(0)) {
+                return Err(Error::io(ErrorQueue::FailRead, "get info of part", self.info_push_file_name(part_id), e).with_part(part_id));
+            }
+            self.parse_info_push(ff_info_push, part_id)
+        });
+
+        let (right_edge, count_pushed) = match res {
+            // the writer restores the info of its part from the records
+            Err(e) if e.kind() == ErrorQueue::InvalidInfoChecksum && self.mode == Mode::ReadWrite && part_id == self.id => {
+                warn!("queue:{}:{} {}, recover info of part from its records", self.name, part_id, e);
+                let (right_edge, count_pushed) = scan_part(self, part_id)?;
+                self.right_edge = right_edge;
+                self.count_pushed = count_pushed;
+                self.put_info_push()?;
+                (right_edge, count_pushed)
+            },
+            res => res?,
+        };
 
         self.right_edge = right_edge;
         self.count_pushed = count_pushed;
@@ -318,4 +728,152 @@ impl Queue {
         //info!("queue ({}): count_pushed:{}, right_edge:{}, id:{}, ready:{}", self.name, self.count_pushed, self.right_edge, self.id, self.is_ready);
         Ok(())
     }
+
+    // Reads right edge and count of pushed records of the part, the state of the queue is not changed
+    pub(crate) fn read_info_of_part(&self, part_id: u32) -> Result<(u64, u32)> {
+        read_info_with_retry(|| match File::open(self.info_push_file_name(part_id)) {
+            Ok(f) => self.parse_info_push(&f, part_id),
+            Err(e) => Err(Error::io(ErrorQueue::NotFound, "read info of part", self.info_push_file_name(part_id), e).with_part(part_id)),
+        })
+    }
+
+    fn parse_info_push(&self, ff_info_push: &File, part_id: u32) -> Result<(u64, u32)> {
+        let mut right_edge = 0;
+        let mut count_pushed = 0;
+
+        if let Some(line) = BufReader::new(ff_info_push).lines().next() {
+            match line {
+                Ok(ll) => {
+                    if !is_valid_info_line(&ll) {
+                        return Err(Error::new(ErrorQueue::InvalidInfoChecksum, "get info of part")
+                            .with_path(self.info_push_file_name(part_id))
+                            .with_part(part_id));
+                    }
+
+                    if let Ok((queue_name, position, pushed, _crc)) = scan_fmt!(&ll, "{};{};{};{}", String, u64, u32, String) {
+                        if queue_name != self.name {
+                            return Err(Error::new(ErrorQueue::Other, "get info of part, queue name mismatch")
+                                .with_path(self.info_push_file_name(part_id))
+                                .with_part(part_id));
+                        }
+
+                        right_edge = position;
+                        count_pushed = pushed;
+                    } else {
+                        return Err(Error::new(ErrorQueue::Other, "get info of part, invalid format")
+                            .with_path(self.info_push_file_name(part_id))
+                            .with_part(part_id));
+                    }
+                },
+                Err(e) => {
+                    return Err(Error::io(ErrorQueue::Other, "get info of part", self.info_push_file_name(part_id), e).with_part(part_id));
+                },
+            }
+        }
+
+        Ok((right_edge, count_pushed))
+    }
+
+    // Ids of the parts found in the queue directory, in ascending order
+    pub fn part_ids(&self) -> Result<Vec<u32>> {
+        let prefix = self.name.to_owned() + "-";
+
+        let entries = match read_dir(&self.base_path) {
+            Ok(entries) => entries,
+            Err(e) => return Err(Error::io(ErrorQueue::FailRead, "read queue path", &self.base_path, e)),
+        };
+
+        let mut ids: Vec<u32> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().to_str()?.strip_prefix(&prefix)?.parse::<u32>().ok())
+            .collect();
+        ids.sort_unstable();
+
+        Ok(ids)
+    }
+
+    // Position of the next published record after the position (part id, pos, count of read records),
+    // the records of the next parts are found when the part is read to the end. None if there are no new records
+    pub(crate) fn next_record_position(&self, part_id: u32, pos: u64, count: u32) -> Result<Option<(u32, u64, u32)>> {
+        let (mut part_id, mut pos, mut count) = (part_id, pos, count);
+
+        loop {
+            let count_pushed = match self.read_info_of_part(part_id) {
+                Ok((_, count_pushed)) => count_pushed,
+                Err(e) if e.kind() == ErrorQueue::NotFound => 0,
+                Err(e) => return Err(e),
+            };
+
+            if count < count_pushed {
+                return Ok(Some((part_id, pos, count)));
+            }
+
+            match self.part_ids()?.into_iter().find(|id| *id > part_id) {
+                Some(next) => {
+                    part_id = next;
+                    pos = 0;
+                    count = 0;
+                },
+                None => return Ok(None),
+            }
+        }
+    }
+
+    pub(crate) fn part_file_name(&self, part_id: u32) -> String {
+        self.base_path.to_owned() + "/" + &self.name + "-" + &part_id.to_string() + "/" + &self.name + "_queue"
+    }
+
+    pub(crate) fn info_push_file_name(&self, part_id: u32) -> String {
+        self.base_path.to_owned() + "/" + &self.name + "-" + &part_id.to_string() + "/" + &self.name + "_info_push"
+    }
+
+    pub(crate) fn index_file_name(&self, part_id: u32) -> String {
+        self.base_path.to_owned() + "/" + &self.name + "-" + &part_id.to_string() + "/" + &self.name + "_index"
+    }
+
+    pub(crate) fn time_index_file_name(&self, part_id: u32) -> String {
+        self.base_path.to_owned() + "/" + &self.name + "-" + &part_id.to_string() + "/" + &self.name + "_time_index"
+    }
+
+    #[cfg(feature = "schema")]
+    pub(crate) fn schema_ids_file_name(&self, part_id: u32) -> String {
+        self.base_path.to_owned() + "/" + &self.name + "-" + &part_id.to_string() + "/" + &self.name + "_schema_ids"
+    }
+
+    pub(crate) fn info_queue_file_name(&self) -> String {
+        self.base_path.to_owned() + "/" + &self.name + "_info_queue"
+    }
+}
+
+// Checks the crc at the end of a line of an info file, the crc is computed over the line before it
+fn is_valid_info_line(line: &str) -> bool {
+    match line.rsplit_once(';') {
+        Some((p, crc)) => {
+            let mut hash = Hasher::new();
+            hash.update(p.as_bytes());
+            hash.update(b";");
+            crc.parse::<u32>().ok() == Some(hash.finalize())
+        },
+        None => false,
+    }
+}
+
+// Reads an info file again while its line has an invalid checksum, the line may be read while the writer rewrites it
+fn read_info_with_retry<T, F>(mut read: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut delay = INFO_RETRY_DELAY;
+    for _ in 1..INFO_READ_ATTEMPTS {
+        match read() {
+            Err(e) if e.kind() == ErrorQueue::InvalidInfoChecksum => {
+                debug!("{}, read again", e);
+                thread::sleep(delay);
+                delay *= 2;
+            },
+            res => return res,
+        }
+    }
+    read()
 }
@@ -4,9 +4,14 @@ pub const HEADER_SIZE: usize = 25;
 pub const MAGIC_MARKER: u32 = 0xEEEF_FEEE;
 pub const MAGIC_MARKER_BYTES: [u8; 4] = [0xEE, 0xFE, 0xEF, 0xEE];
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub enum ErrorQueue {
     NotReady = -911,
+    InFlightLimit = -13,
+    InvalidSchema = -12,
+    UnknownMsgType = -11,
+    InvalidInfoChecksum = -10,
+    InvalidHeader = -9,
     AlreadyOpen = -8,
     FailWrite = -7,
     InvalidChecksum = -6,
@@ -24,34 +29,63 @@ pub enum Mode {
     Default = 2,
 }
 
-#[derive(PartialEq, Debug)]
-#[repr(u8)]
+// Types from USER_MSG_TYPE_MIN to 255 are left to applications
+pub const USER_MSG_TYPE_MIN: u8 = 0x80;
+
+#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
 pub enum MsgType {
-    String = b'S',
-    Object = b'O',
+    String,
+    Object,
+    Binary,
+    Json,
+    Cbor,
+    // marks the deletion of the key given in the body
+    Tombstone,
+    // user-defined type, from USER_MSG_TYPE_MIN to 255
+    User(u8),
+    // type unknown to this version, written by a newer writer. Records of this type can't be pushed
+    Unknown(u8),
 }
 
 impl From<u8> for MsgType {
     fn from(t: u8) -> Self {
-        if t == b'O' {
-            MsgType::Object
-        } else {
-            MsgType::String
+        match t {
+            b'S' => MsgType::String,
+            b'O' => MsgType::Object,
+            b'B' => MsgType::Binary,
+            b'J' => MsgType::Json,
+            b'C' => MsgType::Cbor,
+            b'T' => MsgType::Tombstone,
+            t if t >= USER_MSG_TYPE_MIN => MsgType::User(t),
+            t => MsgType::Unknown(t),
         }
     }
 }
 
 impl MsgType {
-    fn as_u8(&self) -> u8 {
-        if *self == MsgType::Object {
-            b'O'
-        } else {
-            b'S'
+    pub fn as_u8(&self) -> u8 {
+        match *self {
+            MsgType::String => b'S',
+            MsgType::Object => b'O',
+            MsgType::Binary => b'B',
+            MsgType::Json => b'J',
+            MsgType::Cbor => b'C',
+            MsgType::Tombstone => b'T',
+            MsgType::User(t) | MsgType::Unknown(t) => t,
         }
     }
+
+    // The type is read back unchanged after it is written, User types below USER_MSG_TYPE_MIN are not
+    pub fn is_valid(&self) -> bool {
+        !matches!(self, MsgType::Unknown(_)) && MsgType::from(self.as_u8()) == *self
+    }
 }
 
 impl ErrorQueue {
+    pub fn code(&self) -> i32 {
+        *self as i32
+    }
+
     pub fn as_str(&self) -> &'static str {
         match *self {
             ErrorQueue::NotFound => "not found",
@@ -63,11 +97,16 @@ impl ErrorQueue {
             ErrorQueue::NotReady => "not ready",
             ErrorQueue::FailReadTailMessage => "fail read tail message",
             ErrorQueue::InvalidChecksum => "invalid checksum",
+            ErrorQueue::InvalidHeader => "invalid header",
+            ErrorQueue::InvalidInfoChecksum => "invalid checksum of info",
+            ErrorQueue::UnknownMsgType => "unknown message type",
+            ErrorQueue::InvalidSchema => "invalid schema",
+            ErrorQueue::InFlightLimit => "limit of messages in flight",
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Header {
     pub start_pos: u64,
     pub msg_length: u32,
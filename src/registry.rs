@@ -0,0 +1,180 @@
+use crate::checkpoint::*;
+use crate::consumer::{check_record_boundary, find_record};
+use crate::error::{Error, Result};
+use crate::queue::Queue;
+use crate::record::*;
+use fs2::FileExt;
+use std::fs::*;
+use std::path::Path;
+use std::time::SystemTime;
+
+// Consumer registered in the queue directory by its _info_pop file
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConsumerInfo {
+    pub name: String,
+    pub part_id: u32,
+    pub pos: u64,
+    pub count_popped: u32,
+    // number of published messages after the position of the consumer
+    pub lag: u64,
+    // modification time of the _info_pop file
+    pub last_commit: Option<SystemTime>,
+    // the consumer is running and holds its lock
+    pub is_locked: bool,
+}
+
+// Lists and changes the consumers of a queue
+pub struct ConsumerRegistry {
+    queue: Queue,
+}
+
+impl ConsumerRegistry {
+    pub fn new(base_path: &str, queue_name: &str) -> Result<ConsumerRegistry> {
+        Ok(ConsumerRegistry {
+            queue: Queue::new(base_path, queue_name, Mode::Read)?,
+        })
+    }
+
+    // Consumers of the queue ordered by name
+    pub fn list(&self) -> Result<Vec<ConsumerInfo>> {
+        let prefix = self.queue.name.to_owned() + "_info_pop_";
+
+        let entries = match read_dir(&self.queue.base_path) {
+            Ok(entries) => entries,
+            Err(e) => return Err(Error::io(ErrorQueue::FailRead, "list consumers", &self.queue.base_path, e)),
+        };
+
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .filter_map(|entry| entry.file_name().to_str()?.strip_prefix(&prefix).map(|name| name.to_owned()))
+            .filter(|name| ![".lock", PREV_SUFFIX, TMP_SUFFIX].iter().any(|suffix| name.ends_with(suffix)))
+            .collect();
+        names.sort_unstable();
+
+        let mut consumers = Vec::with_capacity(names.len());
+        for name in names {
+            match self.get(&name) {
+                Ok(info) => consumers.push(info),
+                Err(e) => warn!("[queue:registry] {}: skip consumer {}, err={}", self.queue.name, name, e),
+            }
+        }
+        Ok(consumers)
+    }
+
+    pub fn get(&self, consumer_name: &str) -> Result<ConsumerInfo> {
+        let file_name = self.info_pop_file_name(consumer_name);
+
+        let checkpoint = match read_checkpoint(&file_name, &self.queue.name, consumer_name)? {
+            Some(checkpoint) => checkpoint,
+            None => return Err(Error::new(ErrorQueue::NotFound, "get consumer").with_path(file_name)),
+        };
+
+        Ok(ConsumerInfo {
+            name: consumer_name.to_owned(),
+            part_id: checkpoint.part_id,
+            pos: checkpoint.pos,
+            count_popped: checkpoint.count_popped,
+            lag: self.lag(checkpoint.part_id, checkpoint.count_popped)?,
+            last_commit: metadata(&file_name).and_then(|m| m.modified()).ok(),
+            is_locked: self.is_locked(consumer_name),
+        })
+    }
+
+    // Moves the stopped consumer to the record seq (starting from 1) of the part, seq = count_pushed + 1 moves to the end of the part
+    pub fn reset(&self, consumer_name: &str, part_id: u32, seq: u32) -> Result<ConsumerInfo> {
+        self.get(consumer_name)?;
+
+        let lock = self.lock_consumer(consumer_name)?;
+        let res = self.write_position(consumer_name, part_id, seq);
+        self.unlock_consumer(consumer_name, lock);
+        res?;
+
+        self.get(consumer_name)
+    }
+
+    // Removes the position and the lock file of the stopped consumer
+    pub fn delete(&self, consumer_name: &str) -> Result<()> {
+        self.get(consumer_name)?;
+
+        let lock = self.lock_consumer(consumer_name)?;
+        let res = remove_checkpoint(&self.info_pop_file_name(consumer_name));
+        self.unlock_consumer(consumer_name, lock);
+        res?;
+
+        info!("[queue:registry] {}: delete consumer {}", self.queue.name, consumer_name);
+        Ok(())
+    }
+
+    fn write_position(&self, consumer_name: &str, part_id: u32, seq: u32) -> Result<()> {
+        let mut queue = Queue::new(&self.queue.base_path, &self.queue.name, Mode::Read)?;
+        if !Path::new(&queue.part_file_name(part_id)).exists() {
+            return Err(Error::new(ErrorQueue::NotFound, "reset consumer, part not found").with_part(part_id).with_path(queue.part_file_name(part_id)));
+        }
+        queue.open_part(part_id)?;
+        let pos = find_record(&mut queue, seq)?;
+        check_record_boundary(&mut queue, pos, seq - 1)?;
+
+        let checkpoint = Checkpoint {
+            part_id,
+            pos,
+            count_popped: seq - 1,
+        };
+        write_checkpoint(&self.info_pop_file_name(consumer_name), &self.queue.name, consumer_name, &checkpoint)?;
+
+        info!("[queue:registry] {}: reset consumer {} to part {}, position {}, count popped {}", self.queue.name, consumer_name, part_id, pos, seq - 1);
+        Ok(())
+    }
+
+    fn lag(&self, part_id: u32, count_popped: u32) -> Result<u64> {
+        let mut lag = 0;
+        for id in self.queue.part_ids()?.into_iter().filter(|id| *id >= part_id) {
+            let count_pushed = match self.queue.read_info_of_part(id) {
+                Ok((_, count_pushed)) => count_pushed,
+                Err(e) if e.kind() == ErrorQueue::NotFound => 0,
+                Err(e) => return Err(e),
+            };
+
+            lag += if id == part_id { count_pushed.saturating_sub(count_popped) } else { count_pushed } as u64;
+        }
+        Ok(lag)
+    }
+
+    fn is_locked(&self, consumer_name: &str) -> bool {
+        match OpenOptions::new().read(true).write(true).open(self.lock_file_name(consumer_name)) {
+            // the lock is released when the file is closed
+            Ok(f) => f.try_lock_exclusive().is_err(),
+            Err(_) => false,
+        }
+    }
+
+    // Takes the lock of the consumer, a running consumer can't be changed
+    fn lock_consumer(&self, consumer_name: &str) -> Result<File> {
+        let lock_name = self.lock_file_name(consumer_name);
+
+        let file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&lock_name) {
+            Ok(f) => f,
+            Err(e) => return Err(Error::io(ErrorQueue::FailOpen, "open consumer lock", lock_name, e)),
+        };
+        if let Err(e) = file.try_lock_exclusive() {
+            return Err(Error::io(ErrorQueue::AlreadyOpen, "lock consumer", lock_name, e));
+        }
+        Ok(file)
+    }
+
+    fn unlock_consumer(&self, consumer_name: &str, lock: File) {
+        let lock_name = self.lock_file_name(consumer_name);
+        if let Err(e) = remove_file(&lock_name) {
+            warn!("[queue:registry] {}: fail remove lock file {}, err={}", self.queue.name, lock_name, e);
+        }
+        drop(lock);
+    }
+
+    fn info_pop_file_name(&self, consumer_name: &str) -> String {
+        self.queue.base_path.to_owned() + "/" + &self.queue.name + "_info_pop_" + consumer_name
+    }
+
+    fn lock_file_name(&self, consumer_name: &str) -> String {
+        self.info_pop_file_name(consumer_name) + ".lock"
+    }
+}
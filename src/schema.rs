@@ -0,0 +1,519 @@
+use crate::error::{Error, Result};
+use crate::queue::Queue;
+use crate::record::*;
+use fs2::FileExt;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::*;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+
+pub const SCHEMAS_DIR_SUFFIX: &str = "_schemas";
+
+// Entries <seq><schema id> of the tagged records of the part, native endian as in the record header
+const SCHEMA_ID_ENTRY_SIZE: u64 = 8;
+
+// Keywords which don't take part in validation and are allowed in the registered schemas
+const ANNOTATION_KEYWORDS: &[&str] = &["$schema", "$id", "$comment", "title", "description", "default", "examples", "deprecated", "readOnly", "writeOnly"];
+const JSON_TYPES: &[&str] = &["object", "array", "string", "number", "integer", "boolean", "null"];
+
+// Version of a JSON Schema document registered for the queue
+#[derive(Debug, PartialEq, Clone)]
+pub struct Schema {
+    pub id: u32,
+    pub name: String,
+    pub version: u32,
+    pub document: Value,
+}
+
+// Schemas of the queue, kept in <base path>/<queue>_schemas: the document of every schema in <id>.json
+// and the list of schemas in the index file, one line <id>;<name>;<version>;<crc of the document> per schema
+pub struct SchemaRegistry {
+    dir: String,
+    schemas: BTreeMap<u32, Schema>,
+    // the ids of the skipped schemas are not given again
+    last_id: u32,
+}
+
+impl SchemaRegistry {
+    pub fn open(base_path: &str, queue_name: &str) -> Result<SchemaRegistry> {
+        let dir = base_path.to_owned() + "/" + queue_name + SCHEMAS_DIR_SUFFIX;
+        if let Err(e) = create_dir_all(&dir) {
+            return Err(Error::io(ErrorQueue::FailWrite, "create schemas path", dir, e));
+        }
+
+        let mut registry = SchemaRegistry {
+            dir,
+            schemas: BTreeMap::new(),
+            last_id: 0,
+        };
+        registry.reload()?;
+        Ok(registry)
+    }
+
+    // Reads the schemas registered by other processes
+    pub fn reload(&mut self) -> Result<()> {
+        let index_file_name = self.index_file_name();
+        let content = match read_to_string(&index_file_name) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(Error::io(ErrorQueue::FailRead, "read schemas", index_file_name, e)),
+        };
+
+        let mut schemas = BTreeMap::new();
+        for line in content.lines().filter(|l| !l.is_empty()) {
+            if let Some(id) = line.split(';').next().and_then(|id| id.parse::<u32>().ok()) {
+                self.last_id = self.last_id.max(id);
+            }
+
+            match self.read_schema(line) {
+                Ok(schema) => {
+                    schemas.insert(schema.id, schema);
+                },
+                Err(e) => warn!("[queue:schema] skip schema [{}], err={}", line, e),
+            }
+        }
+
+        self.schemas = schemas;
+        Ok(())
+    }
+
+    // Registers the document as the next version of the schema, returns the id of the schema.
+    // The id of the last version is returned if its document is the same
+    pub fn register(&mut self, name: &str, document: &str) -> Result<u32> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+            return Err(Error::new(ErrorQueue::Other, "register schema, invalid name").with_path(&self.dir));
+        }
+
+        let document: Value = match serde_json::from_str(document) {
+            Ok(d @ (Value::Object(_) | Value::Bool(_))) => d,
+            _ => return Err(Error::new(ErrorQueue::InvalidSchema, "register schema, document is not a JSON Schema").with_path(&self.dir)),
+        };
+
+        // a keyword the validator doesn't check would let through the documents the schema rejects
+        if let Err(path) = check_keywords(&document, "$") {
+            let source = io::Error::new(io::ErrorKind::InvalidData, format!("{}: unsupported keyword", path));
+            return Err(Error::new(ErrorQueue::InvalidSchema, "register schema, unsupported keyword").with_path(&self.dir).with_source(source));
+        }
+
+        let lock = self.lock()?;
+        let res = self.reload().and_then(|_| self.add(name, document));
+        drop(lock);
+        res
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Schema> {
+        self.schemas.get(&id)
+    }
+
+    pub fn latest(&self, name: &str) -> Option<&Schema> {
+        self.versions(name).pop()
+    }
+
+    // Versions of the schema from the first one
+    pub fn versions(&self, name: &str) -> Vec<&Schema> {
+        let mut versions: Vec<&Schema> = self.schemas.values().filter(|s| s.name == name).collect();
+        versions.sort_by_key(|s| s.version);
+        versions
+    }
+
+    pub fn schemas(&self) -> impl Iterator<Item = &Schema> {
+        self.schemas.values()
+    }
+
+    // Checks that the payload is a JSON document matching the schema
+    pub fn validate(&self, id: u32, payload: &[u8]) -> Result<()> {
+        let schema = match self.get(id) {
+            Some(s) => s,
+            None => return Err(Error::new(ErrorQueue::NotFound, "validate, schema not found").with_path(self.document_file_name(id))),
+        };
+
+        let value: Value = match serde_json::from_slice(payload) {
+            Ok(v) => v,
+            Err(e) => return Err(Error::new(ErrorQueue::InvalidSchema, "validate, payload is not JSON").with_source(e.into())),
+        };
+
+        if let Err(msg) = validate_value(&schema.document, &value, "$") {
+            debug!("[queue:schema] payload does not match schema {} v{}: {}", schema.name, schema.version, msg);
+            let source = io::Error::new(io::ErrorKind::InvalidData, msg);
+            return Err(Error::new(ErrorQueue::InvalidSchema, "validate, payload does not match schema").with_source(source));
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, name: &str, document: Value) -> Result<u32> {
+        let latest = self.latest(name);
+        if let Some(s) = latest.filter(|s| s.document == document) {
+            return Ok(s.id);
+        }
+
+        let schema = Schema {
+            id: self.last_id + 1,
+            name: name.to_owned(),
+            version: latest.map_or(1, |s| s.version + 1),
+            document,
+        };
+
+        let content = schema.document.to_string();
+        let file_name = self.document_file_name(schema.id);
+        let tmp_file_name = file_name.to_owned() + ".tmp";
+        let res = File::create(&tmp_file_name)
+            .and_then(|mut f| {
+                f.write_all(content.as_bytes())?;
+                f.sync_all()
+            })
+            .and_then(|_| rename(&tmp_file_name, &file_name));
+        if let Err(e) = res {
+            return Err(Error::io(ErrorQueue::FailWrite, "register schema, write document", file_name, e));
+        }
+
+        // the schema is registered when its line is in the index
+        let line = format!("{};{};{};{}\n", schema.id, schema.name, schema.version, crc32fast::hash(content.as_bytes()));
+        let index_file_name = self.index_file_name();
+        let res = OpenOptions::new().create(true).append(true).open(&index_file_name).and_then(|mut f| {
+            f.write_all(line.as_bytes())?;
+            f.sync_all()
+        });
+        if let Err(e) = res {
+            return Err(Error::io(ErrorQueue::FailWrite, "register schema", index_file_name, e));
+        }
+
+        info!("[queue:schema] register schema {} v{}, id {}", schema.name, schema.version, schema.id);
+        let id = schema.id;
+        self.last_id = id;
+        self.schemas.insert(id, schema);
+        Ok(id)
+    }
+
+    fn read_schema(&self, line: &str) -> Result<Schema> {
+        let (id, name, version, crc) = match scan_fmt!(line, "{};{};{};{}", u32, String, u32, u32) {
+            Ok(v) => v,
+            Err(_) => return Err(Error::new(ErrorQueue::Other, "read schema, invalid format").with_path(self.index_file_name())),
+        };
+
+        let file_name = self.document_file_name(id);
+        let content = match read(&file_name) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::io(ErrorQueue::FailRead, "read schema", file_name, e)),
+        };
+
+        if crc32fast::hash(&content) != crc {
+            return Err(Error::new(ErrorQueue::InvalidChecksum, "read schema").with_path(file_name));
+        }
+
+        match serde_json::from_slice(&content) {
+            Ok(document) => Ok(Schema {
+                id,
+                name,
+                version,
+                document,
+            }),
+            Err(e) => Err(Error::new(ErrorQueue::InvalidSchema, "read schema").with_path(file_name).with_source(e.into())),
+        }
+    }
+
+    fn lock(&self) -> Result<File> {
+        let lock_name = self.dir.to_owned() + "/.lock";
+
+        let file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&lock_name) {
+            Ok(f) => f,
+            Err(e) => return Err(Error::io(ErrorQueue::FailOpen, "open schemas lock", lock_name, e)),
+        };
+        if let Err(e) = file.lock_exclusive() {
+            return Err(Error::io(ErrorQueue::FailOpen, "lock schemas", lock_name, e));
+        }
+        Ok(file)
+    }
+
+    fn index_file_name(&self) -> String {
+        self.dir.to_owned() + "/index"
+    }
+
+    fn document_file_name(&self, id: u32) -> String {
+        self.dir.to_owned() + "/" + &id.to_string() + ".json"
+    }
+}
+
+// Checks the value against the subset of JSON Schema: type, enum, const, properties, required, additionalProperties,
+// items, minItems, maxItems, minLength, maxLength, minimum, maximum, exclusiveMinimum, exclusiveMaximum, allOf, anyOf, oneOf, not.
+// Schemas with other keywords are not registered, see check_keywords. Returns the path of the first mismatched value
+fn validate_value(schema: &Value, value: &Value, path: &str) -> std::result::Result<(), String> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return Err(path.to_owned()),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+
+    let fail = |keyword: &str| Err(format!("{}: {}", path, keyword));
+
+    if let Some(t) = schema.get("type") {
+        let matches = match t {
+            Value::String(t) => is_type(value, t),
+            Value::Array(types) => types.iter().filter_map(|t| t.as_str()).any(|t| is_type(value, t)),
+            _ => true,
+        };
+        if !matches {
+            return fail("type");
+        }
+    }
+
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        if !values.contains(value) {
+            return fail("enum");
+        }
+    }
+
+    if schema.get("const").is_some_and(|c| c != value) {
+        return fail("const");
+    }
+
+    match value {
+        Value::Object(object) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                if let Some(name) = required.iter().filter_map(|r| r.as_str()).find(|r| !object.contains_key(*r)) {
+                    return Err(format!("{}.{}: required", path, name));
+                }
+            }
+
+            let properties = schema.get("properties").and_then(|p| p.as_object());
+            for (name, v) in object {
+                let item_path = format!("{}.{}", path, name);
+                match (properties.and_then(|p| p.get(name)), schema.get("additionalProperties")) {
+                    (Some(s), _) | (None, Some(s)) => validate_value(s, v, &item_path)?,
+                    (None, None) => {},
+                }
+            }
+        },
+        Value::Array(items) => {
+            if schema.get("minItems").and_then(|m| m.as_u64()).is_some_and(|m| (items.len() as u64) < m) {
+                return fail("minItems");
+            }
+            if schema.get("maxItems").and_then(|m| m.as_u64()).is_some_and(|m| (items.len() as u64) > m) {
+                return fail("maxItems");
+            }
+            if let Some(s) = schema.get("items") {
+                for (idx, v) in items.iter().enumerate() {
+                    validate_value(s, v, &format!("{}[{}]", path, idx))?;
+                }
+            }
+        },
+        Value::String(s) => {
+            let len = s.chars().count() as u64;
+            if schema.get("minLength").and_then(|m| m.as_u64()).is_some_and(|m| len < m) {
+                return fail("minLength");
+            }
+            if schema.get("maxLength").and_then(|m| m.as_u64()).is_some_and(|m| len > m) {
+                return fail("maxLength");
+            }
+        },
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or_default();
+            let bound = |keyword: &str| schema.get(keyword).and_then(|b| b.as_f64());
+            if bound("minimum").is_some_and(|b| n < b) {
+                return fail("minimum");
+            }
+            if bound("maximum").is_some_and(|b| n > b) {
+                return fail("maximum");
+            }
+            if bound("exclusiveMinimum").is_some_and(|b| n <= b) {
+                return fail("exclusiveMinimum");
+            }
+            if bound("exclusiveMaximum").is_some_and(|b| n >= b) {
+                return fail("exclusiveMaximum");
+            }
+        },
+        _ => {},
+    }
+
+    if let Some(Value::Array(schemas)) = schema.get("allOf") {
+        for s in schemas {
+            validate_value(s, value, path)?;
+        }
+    }
+    if let Some(Value::Array(schemas)) = schema.get("anyOf") {
+        if !schemas.iter().any(|s| validate_value(s, value, path).is_ok()) {
+            return fail("anyOf");
+        }
+    }
+    if let Some(Value::Array(schemas)) = schema.get("oneOf") {
+        if schemas.iter().filter(|s| validate_value(s, value, path).is_ok()).count() != 1 {
+            return fail("oneOf");
+        }
+    }
+    if schema.get("not").is_some_and(|s| validate_value(s, value, path).is_ok()) {
+        return fail("not");
+    }
+
+    Ok(())
+}
+
+// Returns the path of the first keyword of the schema that validate_value doesn't check, or checks in another form
+fn check_keywords(schema: &Value, path: &str) -> std::result::Result<(), String> {
+    let schema = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(schema) => schema,
+        _ => return Err(path.to_owned()),
+    };
+
+    for (keyword, value) in schema {
+        let item_path = format!("{}.{}", path, keyword);
+        match (keyword.as_str(), value) {
+            (k, _) if ANNOTATION_KEYWORDS.contains(&k) => {},
+            ("type", Value::String(t)) if JSON_TYPES.contains(&t.as_str()) => {},
+            ("type", Value::Array(types)) if types.iter().all(|t| t.as_str().is_some_and(|t| JSON_TYPES.contains(&t))) => {},
+            ("enum", Value::Array(_)) | ("const", _) => {},
+            ("required", Value::Array(names)) if names.iter().all(|n| n.is_string()) => {},
+            ("minItems" | "maxItems" | "minLength" | "maxLength", v) if v.is_u64() => {},
+            ("minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum", Value::Number(_)) => {},
+            ("properties", Value::Object(properties)) => {
+                for (name, s) in properties {
+                    check_keywords(s, &format!("{}.{}", item_path, name))?;
+                }
+            },
+            ("additionalProperties" | "items" | "not", s) => check_keywords(s, &item_path)?,
+            ("allOf" | "anyOf" | "oneOf", Value::Array(schemas)) => {
+                for (idx, s) in schemas.iter().enumerate() {
+                    check_keywords(s, &format!("{}[{}]", item_path, idx))?;
+                }
+            },
+            _ => return Err(item_path),
+        }
+    }
+    Ok(())
+}
+
+fn is_type(value: &Value, t: &str) -> bool {
+    match t {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => false,
+    }
+}
+
+// Schema settings of the writer of the queue
+#[derive(Default)]
+pub(crate) struct SchemaState {
+    pub(crate) registry: Option<SchemaRegistry>,
+    // schema of the records pushed by Queue::push
+    pub(crate) schema_id: Option<u32>,
+    // records are validated, records without a schema are not pushed
+    pub(crate) strict: bool,
+    pub(crate) ids: Option<SchemaIdWriter>,
+}
+
+// Schema ids of the tagged records of the part being written
+pub(crate) struct SchemaIdWriter {
+    file: File,
+    file_name: String,
+}
+
+impl SchemaIdWriter {
+    // Opens the schema ids of the part, the entries of the records from seq are dropped, they were not published
+    pub(crate) fn open(queue: &Queue, part_id: u32, seq: u32) -> Result<SchemaIdWriter> {
+        let file_name = queue.schema_ids_file_name(part_id);
+
+        let file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&file_name) {
+            Ok(f) => f,
+            Err(e) => return Err(Error::io(ErrorQueue::FailOpen, "open schema ids", file_name, e).with_part(part_id)),
+        };
+
+        let mut writer = SchemaIdWriter {
+            file,
+            file_name,
+        };
+
+        let entries = writer.read_entries()?;
+        let count = entries.iter().take_while(|(s, _)| *s < seq).count() as u64;
+        if let Err(e) = writer.file.set_len(count * SCHEMA_ID_ENTRY_SIZE) {
+            return Err(Error::io(ErrorQueue::FailWrite, "open schema ids", writer.file_name, e).with_part(part_id));
+        }
+        Ok(writer)
+    }
+
+    pub(crate) fn add(&mut self, seq: u32, schema_id: u32) -> Result<()> {
+        let mut buf = [0; SCHEMA_ID_ENTRY_SIZE as usize];
+        buf[0..4].copy_from_slice(&seq.to_ne_bytes());
+        buf[4..8].copy_from_slice(&schema_id.to_ne_bytes());
+
+        if let Err(e) = self.file.seek(SeekFrom::End(0)).and_then(|_| self.file.write_all(&buf)) {
+            return Err(Error::io(ErrorQueue::FailWrite, "add schema id", &self.file_name, e));
+        }
+        Ok(())
+    }
+
+    fn read_entries(&mut self) -> Result<Vec<(u32, u32)>> {
+        let mut buf = Vec::new();
+        if let Err(e) = self.file.seek(SeekFrom::Start(0)).and_then(|_| self.file.read_to_end(&mut buf)) {
+            return Err(Error::io(ErrorQueue::FailRead, "read schema ids", &self.file_name, e));
+        }
+        Ok(parse_entries(&buf))
+    }
+}
+
+// Schema ids of the tagged records of a part, read by consumers. New entries are read when a record after the read ones is looked up
+#[derive(Debug, Default)]
+pub(crate) struct SchemaIds {
+    part_id: u32,
+    len: u64,
+    entries: Vec<(u32, u32)>,
+}
+
+impl SchemaIds {
+    pub(crate) fn get(&mut self, queue: &Queue, part_id: u32, seq: u32) -> Option<u32> {
+        if part_id != self.part_id {
+            *self = SchemaIds {
+                part_id,
+                ..Default::default()
+            };
+        }
+
+        if self.entries.last().is_none_or(|(s, _)| *s < seq) {
+            self.read_new(queue, seq);
+        }
+
+        let idx = self.entries.partition_point(|(s, _)| *s < seq);
+        self.entries.get(idx).filter(|(s, _)| *s == seq).map(|(_, id)| *id)
+    }
+
+    // Reads the entries up to the record seq, the entries after it may be of records which are not published yet
+    // and are dropped by the writer when their transaction is aborted
+    fn read_new(&mut self, queue: &Queue, seq: u32) {
+        let file_name = queue.schema_ids_file_name(self.part_id);
+        if !Path::new(&file_name).exists() {
+            return;
+        }
+
+        let mut buf = Vec::new();
+        let res = File::open(&file_name).and_then(|mut f| {
+            // the file is truncated by the writer, the entries are read again
+            if f.metadata()?.len() < self.len {
+                self.len = 0;
+                self.entries.clear();
+            }
+            f.seek(SeekFrom::Start(self.len))?;
+            f.read_to_end(&mut buf)
+        });
+        match res {
+            Ok(_) => {
+                let new_entries: Vec<(u32, u32)> = parse_entries(&buf).into_iter().take_while(|(s, _)| *s <= seq).collect();
+                self.len += new_entries.len() as u64 * SCHEMA_ID_ENTRY_SIZE;
+                self.entries.extend(new_entries);
+            },
+            Err(e) => warn!("[queue:schema] fail read schema ids {}, err={}", file_name, e),
+        }
+    }
+}
+
+fn parse_entries(buf: &[u8]) -> Vec<(u32, u32)> {
+    buf.chunks_exact(SCHEMA_ID_ENTRY_SIZE as usize)
+        .map(|e| (u32::from_ne_bytes(e[0..4].try_into().unwrap_or_default()), u32::from_ne_bytes(e[4..8].try_into().unwrap_or_default())))
+        .collect()
+}
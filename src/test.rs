@@ -1,7 +1,20 @@
-use crate::consumer::Consumer;
+use crate::ack::AckConsumer;
+use crate::bridge::Bridge;
+use crate::checkpoint::{LEGACY_INFO_POP_PREFIX, PREV_SUFFIX, TMP_SUFFIX};
+use crate::consumer::{CommitPolicy, Consumer, ConsumerState, StartPosition};
+use crate::dlq::{DeadLetter, DLQ_SUFFIX, REASON_INVALID_CHECKSUM};
+use crate::filter::{MessageFilter, Property};
+use crate::group::GroupConsumer;
+use crate::index::{PartIndex, INDEX_INTERVAL};
+use crate::message::{Message, MessageId};
+use crate::mmap::PartMap;
+use crate::producer::QueueProducer;
 use crate::queue::Queue;
-use crate::record::{ErrorQueue, Mode, MsgType};
-use std::time::Duration;
+use crate::record::{ErrorQueue, Mode, MsgType, HEADER_SIZE, USER_MSG_TYPE_MIN};
+use crate::registry::ConsumerRegistry;
+use crate::time_index::PartTimeIndex;
+use std::io::{Seek, SeekFrom, Write};
+use std::time::{Duration, SystemTime};
 use std::{fs, thread};
 
 fn check_message_integrity(received_numbers: &[i32]) {
@@ -41,13 +54,13 @@ fn test_read_only_mode() {
     let consumer_name = "consumer";
     let mut consumer = Consumer::new(&base_path, consumer_name, queue_name).unwrap();
     let mut received_messages = Vec::new();
-    while consumer.pop_header() {
+    while consumer.pop_header().unwrap().is_some() {
         let msg_size = consumer.header.msg_length as usize;
         let mut msg = vec![0; msg_size];
         if consumer.pop_body(&mut msg).is_ok() {
             let message = String::from_utf8(msg).unwrap();
             received_messages.push(message);
-            consumer.commit();
+            consumer.commit().unwrap();
         } else {
             break;
         }
@@ -85,13 +98,13 @@ fn test_queue_consumer_interaction() {
     // Каждый потребитель читает свою часть сообщений
     let mut received_numbers = vec![Vec::new(); num_consumers];
     for (i, consumer) in consumers.iter_mut().enumerate() {
-        while consumer.pop_header() {
+        while consumer.pop_header().unwrap().is_some() {
             let msg_size = consumer.header.msg_length as usize;
             let mut msg = vec![0; msg_size];
             if consumer.pop_body(&mut msg).is_ok() {
                 let number = String::from_utf8(msg).unwrap().parse::<i32>().unwrap();
                 received_numbers[i].push(number);
-                consumer.commit();
+                consumer.commit().unwrap();
             } else {
                 break;
             }
@@ -120,13 +133,13 @@ fn test_queue_consumer_interaction() {
     // Каждый потребитель читает свою часть новых сообщений
     let mut new_received_numbers = vec![Vec::new(); num_consumers];
     for (i, consumer) in consumers.iter_mut().enumerate() {
-        while consumer.pop_header() {
+        while consumer.pop_header().unwrap().is_some() {
             let msg_size = consumer.header.msg_length as usize;
             let mut msg = vec![0; msg_size];
             if consumer.pop_body(&mut msg).is_ok() {
                 let number = String::from_utf8(msg).unwrap().parse::<i32>().unwrap();
                 new_received_numbers[i].push(number);
-                consumer.commit();
+                consumer.commit().unwrap();
             } else {
                 break;
             }
@@ -159,11 +172,11 @@ fn test_consumer_reconnect() {
     let mut consumer = Consumer::new(&base_path, consumer_name, queue_name).unwrap();
     let num_read_messages = 5;
     for _ in 0..num_read_messages {
-        if consumer.pop_header() {
+        if consumer.pop_header().unwrap().is_some() {
             let msg_size = consumer.header.msg_length as usize;
             let mut msg = vec![0; msg_size];
             consumer.pop_body(&mut msg).unwrap();
-            consumer.commit();
+            consumer.commit().unwrap();
         }
     }
 
@@ -173,13 +186,13 @@ fn test_consumer_reconnect() {
 
     // Читаем оставшиеся сообщения
     let mut received_numbers = Vec::new();
-    while consumer.pop_header() {
+    while consumer.pop_header().unwrap().is_some() {
         let msg_size = consumer.header.msg_length as usize;
         let mut msg = vec![0; msg_size];
         if consumer.pop_body(&mut msg).is_ok() {
             let number = String::from_utf8(msg).unwrap().parse::<i32>().unwrap();
             received_numbers.push(number);
-            consumer.commit();
+            consumer.commit().unwrap();
         } else {
             break;
         }
@@ -194,10 +207,32 @@ use uuid::Uuid;
 fn create_unique_queue_path(base_path: &str, prefix: &str) -> String {
     let uuid = Uuid::new_v4().to_string();
     let path = format!("{}/{}_{}", base_path, prefix, uuid);
-    fs::remove_dir_all(&base_path).unwrap_or_default();
+    fs::remove_dir_all(base_path).unwrap_or_default();
     path
 }
 
+#[test]
+fn test_error_context() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+
+    // Открываем на чтение очередь, которой нет: ошибка содержит операцию, путь и исходную ошибку ввода-вывода
+    let err = Queue::new(&base_path, "unknown_queue", Mode::Read).err().unwrap();
+    assert_eq!(err, ErrorQueue::NotReady);
+    assert_eq!(err.code(), -911);
+    assert!(err.path().unwrap().ends_with("unknown_queue_info_queue"));
+    assert_eq!(err.io_error().unwrap().kind(), std::io::ErrorKind::NotFound);
+    assert!(std::error::Error::source(&err).is_some());
+    assert!(err.to_string().contains("unknown_queue_info_queue"));
+
+    // Запись в очередь, открытую только на чтение
+    drop(Queue::new(&base_path, "test_queue", Mode::ReadWrite).unwrap());
+    let mut queue = Queue::new(&base_path, "test_queue", Mode::Read).unwrap();
+    let err = queue.push(b"0", MsgType::String).unwrap_err();
+    assert_eq!(err.kind(), ErrorQueue::NotReady);
+    assert_eq!(err.part_id(), Some(queue.id));
+    assert_eq!(ErrorQueue::from(err), ErrorQueue::NotReady);
+}
+
 #[test]
 fn test_queue_empty() {
     let base_path = create_unique_queue_path("./test-tmp", "queue");
@@ -210,8 +245,8 @@ fn test_queue_empty() {
     let consumer_name = "consumer";
     let mut consumer = Consumer::new(&base_path, consumer_name, queue_name).unwrap();
 
-    // Проверяем, что метод pop_header возвращает false, если очередь пуста
-    assert!(!consumer.pop_header());
+    // Проверяем, что метод pop_header возвращает None, если очередь пуста
+    assert!(consumer.pop_header().unwrap().is_none());
 }
 
 #[test]
@@ -239,24 +274,24 @@ fn test_multiple_queues() {
     // Читаем сообщения из каждой очереди
     let mut received_numbers_1 = Vec::new();
     let mut received_numbers_2 = Vec::new();
-    while consumer_1.pop_header() {
+    while consumer_1.pop_header().unwrap().is_some() {
         let msg_size = consumer_1.header.msg_length as usize;
         let mut msg = vec![0; msg_size];
         if consumer_1.pop_body(&mut msg).is_ok() {
             let number = String::from_utf8(msg).unwrap().parse::<i32>().unwrap();
             received_numbers_1.push(number);
-            consumer_1.commit();
+            consumer_1.commit().unwrap();
         } else {
             break;
         }
     }
-    while consumer_2.pop_header() {
+    while consumer_2.pop_header().unwrap().is_some() {
         let msg_size = consumer_2.header.msg_length as usize;
         let mut msg = vec![0; msg_size];
         if consumer_2.pop_body(&mut msg).is_ok() {
             let number = String::from_utf8(msg).unwrap().parse::<i32>().unwrap();
             received_numbers_2.push(number);
-            consumer_2.commit();
+            consumer_2.commit().unwrap();
         } else {
             break;
         }
@@ -266,3 +301,1463 @@ fn test_multiple_queues() {
     check_message_integrity(&received_numbers_1);
     check_message_integrity(&received_numbers_2);
 }
+
+#[test]
+fn test_dead_letter_queue() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    // Записываем сообщения одинаковой длины
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    let num_messages = 5;
+    for i in 0..num_messages {
+        let msg = format!("Message {}", i);
+        queue.push(msg.as_bytes(), MsgType::String).unwrap();
+    }
+
+    // Портим тело третьего сообщения прямо в файле части
+    let record_size = (HEADER_SIZE + "Message 0".len()) as u64;
+    let part_path = format!("{}/{}-{}/{}_queue", base_path, queue_name, queue.id, queue_name);
+    let mut ff = fs::OpenOptions::new().write(true).open(part_path).unwrap();
+    ff.seek(SeekFrom::Start(record_size * 2 + HEADER_SIZE as u64)).unwrap();
+    ff.write_all(b"X").unwrap();
+    drop(ff);
+
+    // Потребитель с включенной очередью мертвых писем пропускает испорченное сообщение и читает дальше
+    let mut consumer = Consumer::new(&base_path, "dlq_consumer", queue_name).unwrap();
+    consumer.set_dead_letter_queue(true);
+    let mut received_messages = Vec::new();
+    let mut count_invalid = 0;
+    while consumer.pop_header().unwrap().is_some() {
+        let msg_size = consumer.header.msg_length as usize;
+        let mut msg = vec![0; msg_size];
+        match consumer.pop_body(&mut msg) {
+            Ok(_) => {
+                let message = String::from_utf8(msg).unwrap();
+                if message == "Message 4" {
+                    consumer.reject(message.as_bytes(), "poison message").unwrap();
+                } else {
+                    received_messages.push(message);
+                }
+            },
+            Err(e) if e.kind() == ErrorQueue::InvalidChecksum => count_invalid += 1,
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+        consumer.commit().unwrap();
+    }
+
+    assert_eq!(count_invalid, 1);
+    assert_eq!(received_messages, vec!["Message 0", "Message 1", "Message 3"]);
+
+    // В очереди мертвых писем лежат испорченное и отклоненное сообщения с исходными позициями
+    drop(consumer);
+    let mut dlq_consumer = Consumer::new(&base_path, "dlq_reader", &format!("{}{}", queue_name, DLQ_SUFFIX)).unwrap();
+    let mut letters = Vec::new();
+    while dlq_consumer.pop_header().unwrap().is_some() {
+        let mut msg = vec![0; dlq_consumer.header.msg_length as usize];
+        dlq_consumer.pop_body(&mut msg).unwrap();
+        letters.push(DeadLetter::from_bytes(&msg).unwrap());
+        dlq_consumer.commit().unwrap();
+    }
+
+    assert_eq!(letters.len(), 2);
+    assert_eq!(letters[0].queue_name, queue_name);
+    assert_eq!(letters[0].pos, record_size * 2);
+    assert_eq!(letters[0].count_pushed, 3);
+    assert_eq!(letters[0].reason, REASON_INVALID_CHECKSUM);
+    assert_eq!(letters[0].data, b"Xessage 2");
+    assert_eq!(letters[1].pos, record_size * 4);
+    assert_eq!(letters[1].count_pushed, 5);
+    assert_eq!(letters[1].reason, "poison message");
+    assert_eq!(letters[1].data, b"Message 4");
+}
+
+#[test]
+fn test_dead_letter_queue_shared() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for msg in ["a1", "b1", "a2"] {
+        queue.push(msg.as_bytes(), MsgType::String).unwrap();
+    }
+
+    // Два потребителя по очереди отклоняют сообщения, письма пишутся одним писателем
+    let mut consumer_a = Consumer::new(&base_path, "consumer_a", queue_name).unwrap();
+    let mut consumer_b = Consumer::new(&base_path, "consumer_b", queue_name).unwrap();
+    let reject = |consumer: &mut Consumer, msg: &str| {
+        while let Some(message) = consumer.next_message().unwrap() {
+            if message.as_str() == Some(msg) {
+                consumer.reject(&message.data, "poison message").unwrap();
+                break;
+            }
+        }
+    };
+    reject(&mut consumer_a, "a1");
+    reject(&mut consumer_b, "b1");
+    reject(&mut consumer_a, "a2");
+
+    // Второй писатель очереди не открывается, пока первый жив
+    let dlq_name = format!("{}{}", queue_name, DLQ_SUFFIX);
+    let second = Queue::new(&base_path, &dlq_name, Mode::ReadWrite);
+    assert_eq!(second.err().unwrap().kind(), ErrorQueue::AlreadyOpen);
+
+    let mut dlq_consumer = Consumer::new(&base_path, "dlq_reader", &dlq_name).unwrap();
+    let mut letters = Vec::new();
+    while let Some(message) = dlq_consumer.next_message().unwrap() {
+        letters.push(DeadLetter::from_bytes(&message.data).unwrap().data);
+    }
+    assert_eq!(letters, vec![b"a1".to_vec(), b"b1".to_vec(), b"a2".to_vec()]);
+
+    // После закрытия потребителей писатель закрывается и открывается снова
+    drop(consumer_a);
+    drop(consumer_b);
+    Queue::new(&base_path, &dlq_name, Mode::ReadWrite).unwrap();
+}
+
+#[test]
+fn test_consumer_mmap() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..5 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    // Читаем сообщения напрямую из отображения файла части, без копирования
+    let mut consumer = Consumer::new(&base_path, "mmap_consumer", queue_name).unwrap();
+    consumer.set_mmap(true);
+    let mut received_numbers = Vec::new();
+    while consumer.pop_header().unwrap().is_some() {
+        let body = consumer.pop_body_ref().unwrap();
+        received_numbers.push(std::str::from_utf8(body).unwrap().parse::<i32>().unwrap());
+        consumer.commit().unwrap();
+    }
+    assert_eq!(received_numbers, vec![0, 1, 2, 3, 4]);
+
+    // Писатель дописывает в активную часть, отображение должно расшириться
+    for i in 5..8 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+    while consumer.pop_header().unwrap().is_some() {
+        let mut msg = vec![0; consumer.header.msg_length as usize];
+        consumer.pop_body(&mut msg).unwrap();
+        received_numbers.push(String::from_utf8(msg).unwrap().parse::<i32>().unwrap());
+        consumer.commit().unwrap();
+    }
+
+    // Новая часть очереди
+    drop(queue);
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 8..10 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+    while consumer.pop_header().unwrap().is_some() {
+        let body = consumer.pop_body_ref().unwrap();
+        received_numbers.push(std::str::from_utf8(body).unwrap().parse::<i32>().unwrap());
+        consumer.commit().unwrap();
+    }
+    assert_eq!(received_numbers, (0..10).collect::<Vec<i32>>());
+
+    // После отключения отображения чтение продолжается через файл с той же позиции
+    consumer.set_mmap(false);
+    queue.push(b"10", MsgType::String).unwrap();
+    assert!(consumer.pop_header().unwrap().is_some());
+    let mut msg = vec![0; consumer.header.msg_length as usize];
+    consumer.pop_body(&mut msg).unwrap();
+    assert_eq!(msg, b"10");
+
+    // Отображение укороченной части не выходит за конец файла
+    let mut part_map = PartMap::open(&queue, queue.id).unwrap();
+    part_map.remap(queue.right_edge).unwrap();
+    assert_eq!(part_map.len(), queue.right_edge);
+    let part_file = fs::OpenOptions::new().write(true).open(queue.part_file_name(queue.id)).unwrap();
+    part_file.set_len(HEADER_SIZE as u64).unwrap();
+    part_map.remap(queue.right_edge).unwrap();
+    assert_eq!(part_map.len(), HEADER_SIZE as u64);
+    assert!(part_map.slice(0, HEADER_SIZE + 1).is_none());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_consumer() {
+    use crate::async_queue::{AsyncConsumer, AsyncQueue};
+    use futures_core::Stream;
+    use std::future::poll_fn;
+    use std::pin::Pin;
+
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    rt.block_on(async {
+        let queue = AsyncQueue::new(&base_path, queue_name, Mode::ReadWrite).await.unwrap();
+        queue.push(b"0", MsgType::String).await.unwrap();
+
+        let mut consumer = AsyncConsumer::new(&base_path, "async_consumer", queue_name).await.unwrap();
+        let msg = consumer.next().await.unwrap();
+        assert_eq!(msg.data, b"0");
+        assert_eq!(msg.seq, 1);
+        consumer.commit().await.unwrap();
+
+        // Сообщения записываются позже, потребитель ждет их через поток
+        let writer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            for i in 1..4 {
+                queue.push(format!("{}", i).as_bytes(), MsgType::String).await.unwrap();
+            }
+        });
+
+        let mut received_numbers = Vec::new();
+        while received_numbers.len() < 3 {
+            let msg = poll_fn(|cx| Pin::new(&mut consumer).poll_next(cx)).await.unwrap().unwrap();
+            received_numbers.push(msg.as_str().unwrap().parse::<i32>().unwrap());
+            consumer.commit().await.unwrap();
+        }
+        writer.await.unwrap();
+
+        assert_eq!(received_numbers, vec![1, 2, 3]);
+    });
+}
+
+#[test]
+fn test_wait_for_messages() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    let mut consumer = Consumer::new(&base_path, "wait_consumer", queue_name).unwrap();
+
+    // Новых сообщений нет, ожидание завершается по таймауту
+    assert_eq!(consumer.wait_for_messages(Duration::from_millis(50)), 0);
+
+    // Сообщения записываются в другом потоке, пока потребитель ждет
+    let writer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        for i in 0..3 {
+            queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+        }
+        queue
+    });
+
+    let mut received_numbers = Vec::new();
+    while received_numbers.len() < 3 {
+        assert!(consumer.wait_for_messages(Duration::from_secs(5)) > 0);
+        while consumer.pop_header().unwrap().is_some() {
+            let mut msg = vec![0; consumer.header.msg_length as usize];
+            consumer.pop_body(&mut msg).unwrap();
+            received_numbers.push(String::from_utf8(msg).unwrap().parse::<i32>().unwrap());
+            consumer.commit().unwrap();
+        }
+    }
+    assert_eq!(received_numbers, vec![0, 1, 2]);
+
+    // Писатель открывает новую часть очереди
+    drop(writer.join().unwrap());
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    queue.push(b"3", MsgType::String).unwrap();
+    queue.push(b"4", MsgType::String).unwrap();
+
+    assert_eq!(consumer.wait_for_messages(Duration::from_secs(5)), 2);
+    assert!(consumer.pop_header().unwrap().is_some());
+    let mut msg = vec![0; consumer.header.msg_length as usize];
+    consumer.pop_body(&mut msg).unwrap();
+    assert_eq!(msg, b"3");
+}
+
+#[test]
+fn test_queue_producer() {
+    fn assert_send_sync<T: Send + Sync + Clone>() {}
+    assert_send_sync::<QueueProducer>();
+
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    // Несколько потоков пишут в очередь через общий писатель
+    let producer = QueueProducer::new(Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap()).unwrap();
+    let num_threads = 4;
+    let num_messages = 25;
+    let mut writers = Vec::new();
+    for t in 0..num_threads {
+        let producer = producer.clone();
+        writers.push(thread::spawn(move || {
+            let handles: Vec<_> = (0..num_messages).map(|i| producer.push(format!("{}:{}", t, i).as_bytes(), MsgType::String)).collect();
+            handles.into_iter().map(|h| h.wait().unwrap()).collect::<Vec<u64>>()
+        }));
+    }
+
+    let mut positions: Vec<u64> = writers.into_iter().flat_map(|w| w.join().unwrap()).collect();
+    positions.sort();
+    positions.dedup();
+    assert_eq!(positions.len(), num_threads * num_messages);
+
+    // Все сообщения записаны, порядок сообщений каждого потока сохранен
+    let mut consumer = Consumer::new(&base_path, "producer_consumer", queue_name).unwrap();
+    let mut received = vec![Vec::new(); num_threads];
+    while consumer.pop_header().unwrap().is_some() {
+        let mut msg = vec![0; consumer.header.msg_length as usize];
+        consumer.pop_body(&mut msg).unwrap();
+        let msg = String::from_utf8(msg).unwrap();
+        let (t, i) = msg.split_once(':').unwrap();
+        received[t.parse::<usize>().unwrap()].push(i.parse::<i32>().unwrap());
+        consumer.commit().unwrap();
+    }
+    for numbers in received.iter() {
+        assert_eq!(numbers.len(), num_messages);
+        check_message_integrity(numbers);
+    }
+}
+
+#[test]
+fn test_consumer_recover() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..5 {
+        queue.push(format!("Message {}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    // Портим тело третьего сообщения
+    let record_size = (HEADER_SIZE + "Message 0".len()) as u64;
+    let mut ff = fs::OpenOptions::new().write(true).open(queue.part_file_name(queue.id)).unwrap();
+    ff.seek(SeekFrom::Start(record_size * 2 + HEADER_SIZE as u64)).unwrap();
+    ff.write_all(b"X").unwrap();
+    drop(ff);
+
+    let mut consumer = Consumer::new(&base_path, "recover_consumer", queue_name).unwrap();
+    let mut received_messages = Vec::new();
+    while let Some(header) = consumer.pop_header().unwrap() {
+        let mut msg = vec![0; header.msg_length as usize];
+        match consumer.pop_body(&mut msg) {
+            Ok(_) => {
+                received_messages.push(String::from_utf8(msg).unwrap());
+                consumer.commit().unwrap();
+            },
+            Err(e) => {
+                assert_eq!(e.kind(), ErrorQueue::InvalidChecksum);
+                break;
+            },
+        }
+    }
+    assert_eq!(received_messages, vec!["Message 0", "Message 1"]);
+
+    // Потребитель не читает и не сохраняет позицию, пока не будет восстановлен
+    assert_eq!(consumer.state(), ConsumerState::InvalidRecord);
+    assert_eq!(consumer.pop_header().unwrap_err().kind(), ErrorQueue::NotReady);
+    assert_eq!(consumer.commit().unwrap_err().kind(), ErrorQueue::NotReady);
+
+    // После восстановления испорченное сообщение пропущено
+    consumer.recover().unwrap();
+    assert_eq!(consumer.state(), ConsumerState::Ready);
+    while let Some(header) = consumer.pop_header().unwrap() {
+        let mut msg = vec![0; header.msg_length as usize];
+        consumer.pop_body(&mut msg).unwrap();
+        received_messages.push(String::from_utf8(msg).unwrap());
+        consumer.commit().unwrap();
+    }
+    assert_eq!(received_messages, vec!["Message 0", "Message 1", "Message 3", "Message 4"]);
+    assert_eq!(consumer.count_popped, 5);
+}
+
+#[test]
+fn test_consumer_messages() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..5 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    // Сообщение содержит тело, тип, номер и позицию записи
+    let mut consumer = Consumer::new(&base_path, "messages_consumer", queue_name).unwrap();
+    let msg = consumer.next_message().unwrap().unwrap();
+    assert_eq!(msg.data, b"0");
+    assert_eq!(msg.msg_type, MsgType::String);
+    assert_eq!(msg.seq, 1);
+    assert_eq!(msg.part_id, queue.id);
+    assert_eq!(msg.pos, 0);
+    consumer.commit().unwrap();
+
+    // Итератор с автоматическим подтверждением: сохраняется позиция последнего обработанного сообщения
+    let mut received_numbers = Vec::new();
+    for msg in consumer.messages().auto_commit(true) {
+        let msg = msg.unwrap();
+        assert_eq!(msg.pos, (HEADER_SIZE as u64 + 1) * (msg.seq as u64 - 1));
+        received_numbers.push(msg.as_str().unwrap().parse::<i32>().unwrap());
+        if received_numbers.len() == 2 {
+            break;
+        }
+    }
+    assert_eq!(received_numbers, vec![1, 2]);
+
+    // Позиция после второго сообщения из итератора сохранена при его удалении
+    let info = fs::read_to_string(consumer.info_pop_file_name()).unwrap();
+    assert!(info.starts_with(&format!("{};messages_consumer;{};3;{};", queue_name, (HEADER_SIZE + 1) * 3, queue.id)));
+
+    let rest: Vec<i32> = consumer.messages().map(|m| m.unwrap().as_str().unwrap().parse::<i32>().unwrap()).collect();
+    assert_eq!(rest, vec![3, 4]);
+    assert!(consumer.next_message().unwrap().is_none());
+}
+
+#[test]
+fn test_commit_policy() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..5 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    let record_size = HEADER_SIZE as u64 + 1;
+    let committed = |consumer: &Consumer| {
+        let info = fs::read_to_string(consumer.info_pop_file_name()).unwrap();
+        info.split(';').nth(3).unwrap().parse::<u32>().unwrap()
+    };
+
+    // Позиция сохраняется перед чтением следующего сообщения, когда прочитано два сообщения
+    let mut consumer = Consumer::new(&base_path, "policy_consumer", queue_name).unwrap();
+    consumer.set_commit_policy(CommitPolicy::EveryN(2));
+    let mut counts = Vec::new();
+    while let Some(header) = consumer.pop_header().unwrap() {
+        counts.push(committed(&consumer));
+        let mut msg = vec![0; header.msg_length as usize];
+        consumer.pop_body(&mut msg).unwrap();
+    }
+    assert_eq!(counts, vec![0, 0, 2, 2, 4]);
+    assert_eq!(committed(&consumer), 4);
+
+    // Непринятые сообщения сохраняются при закрытии потребителя
+    let info_pop_file_name = consumer.info_pop_file_name();
+    drop(consumer);
+    let info = fs::read_to_string(&info_pop_file_name).unwrap();
+    assert!(info.starts_with(&format!("{};policy_consumer;{};5;{};", queue_name, record_size * 5, queue.id)));
+
+    // После каждого сообщения
+    let mut consumer = Consumer::new(&base_path, "every_consumer", queue_name).unwrap();
+    consumer.set_commit_policy(CommitPolicy::EveryMessage);
+    let mut counts = Vec::new();
+    while consumer.next_message().unwrap().is_some() {
+        counts.push(committed(&consumer));
+    }
+    assert_eq!(counts, vec![0, 1, 2, 3, 4]);
+    assert_eq!(committed(&consumer), 5);
+
+    // Только при закрытии
+    let mut consumer = Consumer::new(&base_path, "drop_consumer", queue_name).unwrap();
+    consumer.set_commit_policy(CommitPolicy::OnDrop);
+    while consumer.next_message().unwrap().is_some() {}
+    assert_eq!(committed(&consumer), 0);
+}
+
+#[test]
+fn test_consumer_seek() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    // Две части очереди: 0..5 и 5..8
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..5 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+    drop(queue);
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 5..8 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+    assert_eq!(queue.part_ids().unwrap(), vec![0, 1]);
+
+    let mut consumer = Consumer::new(&base_path, "seek_consumer", queue_name).unwrap();
+    let next_number = |consumer: &mut Consumer| consumer.next_message().unwrap().map(|m| m.as_str().unwrap().parse::<i32>().unwrap());
+
+    // Повтор с начала очереди
+    consumer.seek_to_start().unwrap();
+    assert_eq!((consumer.id, consumer.count_popped), (0, 0));
+    assert_eq!(next_number(&mut consumer), Some(0));
+
+    // Переход к сообщению с номером 4 в текущей части
+    consumer.seek_to_sequence(4).unwrap();
+    assert_eq!(next_number(&mut consumer), Some(3));
+
+    // Переход к части и в конец очереди
+    consumer.seek_to_part(1).unwrap();
+    assert_eq!(next_number(&mut consumer), Some(5));
+    consumer.seek_to_end().unwrap();
+    assert_eq!(next_number(&mut consumer), None);
+
+    // Новая позиция сохранена
+    let info = fs::read_to_string(consumer.info_pop_file_name()).unwrap();
+    assert!(info.starts_with(&format!("{};seek_consumer;{};3;1;", queue_name, (HEADER_SIZE + 1) * 3)));
+
+    // Несуществующие цели не меняют позицию
+    assert_eq!(consumer.seek_to_sequence(0).unwrap_err().kind(), ErrorQueue::NotFound);
+    assert_eq!(consumer.seek_to_sequence(5).unwrap_err().kind(), ErrorQueue::NotFound);
+    assert_eq!(consumer.seek_to_part(7).unwrap_err().kind(), ErrorQueue::NotFound);
+    queue.push(b"8", MsgType::String).unwrap();
+    assert_eq!(next_number(&mut consumer), Some(8));
+
+    // Переход в конец части
+    consumer.seek_to_sequence(2).unwrap();
+    assert_eq!(next_number(&mut consumer), Some(6));
+    consumer.seek_to_sequence(5).unwrap();
+    assert_eq!(next_number(&mut consumer), None);
+}
+
+#[test]
+fn test_consumer_seek_to_time() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+    let interval = Duration::from_millis(20);
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    queue.set_time_index_interval(interval);
+    queue.push(b"0", MsgType::String).unwrap();
+    queue.push(b"1", MsgType::String).unwrap();
+    thread::sleep(interval * 3);
+    let t1 = SystemTime::now();
+    thread::sleep(interval * 2);
+    queue.push(b"2", MsgType::String).unwrap();
+
+    // Вторая часть очереди
+    drop(queue);
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    queue.set_time_index_interval(interval);
+    thread::sleep(interval * 2);
+    let t2 = SystemTime::now();
+    thread::sleep(interval * 2);
+    queue.push(b"3", MsgType::String).unwrap();
+    queue.push(b"4", MsgType::String).unwrap();
+
+    // В индексе первой части одна запись на интервал, первая и последняя записи указывают на сообщения 0 и 2
+    let index = PartTimeIndex::read(&queue, 0).unwrap();
+    assert_eq!((index.entries[0].seq, index.entries[0].pos), (1, 0));
+    assert_eq!(index.entries.last().unwrap().seq, 3);
+    assert!(index.min_ts <= index.max_ts);
+
+    let mut consumer = Consumer::new(&base_path, "time_consumer", queue_name).unwrap();
+    let next_number = |consumer: &mut Consumer| consumer.next_message().unwrap().map(|m| m.as_str().unwrap().parse::<i32>().unwrap());
+
+    consumer.seek_to_time(t1).unwrap();
+    assert_eq!((consumer.id, next_number(&mut consumer)), (0, Some(2)));
+
+    consumer.seek_to_time(t2).unwrap();
+    assert_eq!((consumer.id, next_number(&mut consumer)), (1, Some(3)));
+
+    consumer.seek_to_time(SystemTime::UNIX_EPOCH).unwrap();
+    assert_eq!((consumer.id, next_number(&mut consumer)), (0, Some(0)));
+
+    // Время после последней записи - конец очереди
+    consumer.seek_to_time(SystemTime::now() + Duration::from_secs(3600)).unwrap();
+    assert_eq!(next_number(&mut consumer), None);
+    queue.push(b"5", MsgType::String).unwrap();
+    assert_eq!(next_number(&mut consumer), Some(5));
+}
+
+#[test]
+fn test_queue_index() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    let num_messages = INDEX_INTERVAL * 3 + 10;
+    for i in 0..num_messages {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    // Разреженный индекс: одна запись на INDEX_INTERVAL сообщений
+    let index = PartIndex::read(&queue, queue.id).unwrap();
+    assert_eq!(index.entries.len(), 4);
+    assert_eq!(index.entries[1].seq, INDEX_INTERVAL + 1);
+
+    // Чтение сообщения по номеру
+    let msg = queue.get(100).unwrap();
+    assert_eq!((msg.as_str(), msg.seq, msg.part_id), (Some("99"), 100, queue.id));
+    assert_eq!(queue.get(num_messages).unwrap().as_str(), Some(format!("{}", num_messages - 1).as_str()));
+    assert_eq!(queue.get(0).unwrap_err().kind(), ErrorQueue::NotFound);
+    assert_eq!(queue.get(num_messages + 1).unwrap_err().kind(), ErrorQueue::NotFound);
+
+    // Без файла индекса сообщения читаются, индекс восстанавливается из файла части
+    fs::remove_file(queue.index_file_name(queue.id)).unwrap();
+    assert_eq!(queue.get(150).unwrap().as_str(), Some("149"));
+    queue.rebuild_index(queue.id).unwrap();
+    assert_eq!(PartIndex::read(&queue, queue.id).unwrap(), index);
+
+    // Запись продолжается после восстановления
+    queue.push(b"tail", MsgType::String).unwrap();
+    assert_eq!(queue.get(num_messages + 1).unwrap().as_str(), Some("tail"));
+
+    // Чтение закрытой части через очередь только для чтения, позиция потребителя по номеру
+    drop(queue);
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    queue.push(b"next part", MsgType::String).unwrap();
+    let reader = Queue::new(&base_path, queue_name, Mode::Read).unwrap();
+    assert_eq!(reader.get_in_part(0, 70).unwrap().as_str(), Some("69"));
+
+    let mut consumer = Consumer::new(&base_path, "index_consumer", queue_name).unwrap();
+    consumer.seek_to_part(0).unwrap();
+    consumer.seek_to_sequence(130).unwrap();
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("129"));
+}
+
+#[test]
+fn test_consumer_group() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..10 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    let mut member_a = GroupConsumer::new(&base_path, "workers", "a", queue_name).unwrap();
+    let mut member_b = GroupConsumer::new(&base_path, "workers", "b", queue_name).unwrap();
+    assert_eq!(GroupConsumer::new(&base_path, "workers", "a", queue_name).err().map(|e| e.kind()), Some(ErrorQueue::AlreadyOpen));
+
+    // Участники группы получают разные сообщения, включая сообщения следующей части
+    drop(queue);
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 10..15 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    let mut received = Vec::new();
+    loop {
+        let msg_a = member_a.next_message().unwrap();
+        let msg_b = member_b.next_message().unwrap();
+        if msg_a.is_none() && msg_b.is_none() {
+            break;
+        }
+        received.extend(msg_a.into_iter().chain(msg_b).map(|m| m.as_str().unwrap().parse::<u32>().unwrap()));
+    }
+    received.sort();
+    assert_eq!(received, (0..15).collect::<Vec<u32>>());
+    member_a.commit().unwrap();
+    member_b.commit().unwrap();
+    assert!(member_a.group_state().unwrap().claims.is_empty());
+
+    // Незавершённое сообщение ушедшего участника получает другой участник
+    queue.push(b"15", MsgType::String).unwrap();
+    queue.push(b"16", MsgType::String).unwrap();
+    assert_eq!(member_b.next_message().unwrap().unwrap().as_str(), Some("15"));
+    drop(member_b);
+    assert_eq!(member_a.next_message().unwrap().unwrap().as_str(), Some("15"));
+    assert_eq!(member_a.next_message().unwrap().unwrap().as_str(), Some("16"));
+    assert!(member_a.next_message().unwrap().is_none());
+    assert!(member_a.group_state().unwrap().claims.is_empty());
+}
+
+#[test]
+fn test_ack_consumer() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 1..=5 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    let mut consumer = AckConsumer::new(&base_path, "ack_consumer", queue_name).unwrap();
+    consumer.set_visibility_timeout(Duration::from_millis(100));
+    let info_file_name = base_path.to_owned() + "/" + queue_name + "_info_pop_ack_consumer";
+    let committed = || fs::read_to_string(&info_file_name).unwrap().trim().split(';').nth(3).unwrap().parse::<u32>().unwrap();
+
+    let m1 = consumer.next_message().unwrap().unwrap();
+    let m2 = consumer.next_message().unwrap().unwrap();
+    let m3 = consumer.next_message().unwrap().unwrap();
+    assert_eq!((m1.seq, m2.seq, m3.seq), (1, 2, 3));
+
+    // Позиция сохраняется только после подтверждения всех предыдущих сообщений
+    consumer.ack(m2.id()).unwrap();
+    assert_eq!(committed(), 0);
+    consumer.ack(m1.id()).unwrap();
+    assert_eq!(committed(), 2);
+    assert_eq!(consumer.ack(m1.id()).unwrap_err().kind(), ErrorQueue::NotFound);
+
+    // Отклонённое сообщение выдаётся повторно
+    consumer.nack(m3.id(), Duration::ZERO).unwrap();
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("3"));
+    assert_eq!(consumer.attempts(m3.id()), 2);
+
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("4"));
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("5"));
+    assert!(consumer.next_message().unwrap().is_none());
+    assert_eq!(consumer.count_in_flight(), 3);
+
+    // После таймаута видимости неподтверждённые сообщения выдаются повторно
+    thread::sleep(Duration::from_millis(150));
+    let redelivered: Vec<u32> = (0..3).map(|_| consumer.next_message().unwrap().unwrap().seq).collect();
+    assert_eq!(redelivered, vec![3, 4, 5]);
+
+    for seq in [5, 3, 4] {
+        let id = MessageId {
+            part_id: m1.part_id,
+            seq,
+        };
+        consumer.ack(id).unwrap();
+    }
+    assert_eq!(committed(), 5);
+    assert_eq!(consumer.count_in_flight(), 0);
+
+    // При достижении лимита сообщений в обработке возвращается отдельная ошибка, а не пустой результат
+    for i in 6..=7 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+    consumer.set_max_in_flight(1);
+    let m6 = consumer.next_message().unwrap().unwrap();
+    assert_eq!(consumer.next_message().err().unwrap().kind(), ErrorQueue::InFlightLimit);
+    consumer.ack(m6.id()).unwrap();
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("7"));
+}
+
+#[test]
+fn test_consumer_registry() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..10 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    let mut consumer_a = Consumer::new(&base_path, "a", queue_name).unwrap();
+    for _ in 0..3 {
+        consumer_a.next_message().unwrap().unwrap();
+    }
+    consumer_a.commit().unwrap();
+    assert_eq!(Consumer::new(&base_path, "a", queue_name).err().map(|e| e.kind()), Some(ErrorQueue::AlreadyOpen));
+    drop(Consumer::new(&base_path, "b", queue_name).unwrap());
+
+    // Список потребителей с позицией, отставанием и признаком блокировки
+    let registry = ConsumerRegistry::new(&base_path, queue_name).unwrap();
+    let consumers = registry.list().unwrap();
+    assert_eq!(consumers.iter().map(|c| c.name.as_str()).collect::<Vec<&str>>(), vec!["a", "b"]);
+    assert_eq!((consumers[0].count_popped, consumers[0].lag, consumers[0].is_locked), (3, 7, true));
+    assert_eq!((consumers[1].count_popped, consumers[1].lag, consumers[1].is_locked), (0, 10, false));
+    assert!(consumers[0].last_commit.is_some());
+
+    // Работающего потребителя нельзя изменить
+    assert_eq!(registry.reset("a", queue.id, 6).unwrap_err().kind(), ErrorQueue::AlreadyOpen);
+    drop(consumer_a);
+
+    let info = registry.reset("a", queue.id, 6).unwrap();
+    assert_eq!((info.count_popped, info.lag, info.is_locked), (5, 5, false));
+    assert_eq!(registry.reset("a", queue.id, 12).unwrap_err().kind(), ErrorQueue::NotFound);
+    assert_eq!(registry.reset("c", queue.id, 1).unwrap_err().kind(), ErrorQueue::NotFound);
+
+    registry.delete("b").unwrap();
+    assert_eq!(registry.list().unwrap().len(), 1);
+    assert!(!std::path::Path::new(&(base_path.to_owned() + "/" + queue_name + "_info_pop_b.lock")).exists());
+}
+
+#[test]
+fn test_consumer_peek() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..5 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    let mut consumer = Consumer::new(&base_path, "peek_consumer", queue_name).unwrap();
+    let info = fs::read_to_string(consumer.info_pop_file_name()).unwrap();
+
+    // Просмотр не сдвигает потребителя
+    assert_eq!(consumer.peek().unwrap().unwrap().as_str(), Some("0"));
+    assert_eq!(consumer.peek().unwrap().unwrap().as_str(), Some("0"));
+    let peeked: Vec<String> = consumer.peek_n(3).unwrap().iter().map(|m| m.as_str().unwrap().to_owned()).collect();
+    assert_eq!(peeked, vec!["0", "1", "2"]);
+    assert_eq!(consumer.count_popped, 0);
+    assert_eq!(fs::read_to_string(consumer.info_pop_file_name()).unwrap(), info);
+
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("0"));
+
+    // После чтения заголовка просматривается то же сообщение, чтение тела не нарушается
+    let header = consumer.pop_header().unwrap().unwrap();
+    assert_eq!(consumer.peek().unwrap().unwrap().as_str(), Some("1"));
+    let mut msg = vec![0; header.msg_length as usize];
+    consumer.pop_body(&mut msg).unwrap();
+    assert_eq!(msg, b"1");
+
+    // Просмотр продолжается в следующей части
+    drop(queue);
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    queue.push(b"5", MsgType::String).unwrap();
+    let peeked: Vec<(u32, String)> = consumer.peek_n(10).unwrap().iter().map(|m| (m.part_id, m.as_str().unwrap().to_owned())).collect();
+    assert_eq!(peeked, vec![(0, "2".to_owned()), (0, "3".to_owned()), (0, "4".to_owned()), (queue.id, "5".to_owned())]);
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("2"));
+}
+
+#[test]
+fn test_consumer_checkpoint() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..5 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    let mut consumer = Consumer::new(&base_path, "checkpoint_consumer", queue_name).unwrap();
+    for _ in 0..3 {
+        consumer.next_message().unwrap().unwrap();
+    }
+    consumer.commit().unwrap();
+    while consumer.next_message().unwrap().is_some() {}
+    consumer.commit().unwrap();
+
+    // Позиция записана с контрольной суммой, предыдущая сохранена в .prev
+    let file_name = consumer.info_pop_file_name();
+    let prev_file_name = file_name.to_owned() + PREV_SUFFIX;
+    let info = fs::read_to_string(&file_name).unwrap();
+    assert_eq!(info.trim().split(';').count(), 6);
+    assert!(!std::path::Path::new(&(file_name.to_owned() + TMP_SUFFIX)).exists());
+    consumer.get_info().unwrap();
+    assert_eq!(consumer.count_popped, 5);
+
+    // Повреждённая позиция заменяется предыдущей
+    fs::write(&file_name, info.replace(";5;", ";4;")).unwrap();
+    consumer.get_info().unwrap();
+    assert_eq!(consumer.count_popped, 3);
+
+    // Позиция без файла, как после сбоя между переименованиями
+    fs::remove_file(&file_name).unwrap();
+    let registry = ConsumerRegistry::new(&base_path, queue_name).unwrap();
+    assert_eq!(registry.get("checkpoint_consumer").unwrap().count_popped, 3);
+
+    // Обе позиции повреждены
+    fs::write(&file_name, info.replace(";5;", ";4;")).unwrap();
+    fs::write(&prev_file_name, "garbage").unwrap();
+    assert_eq!(consumer.get_info().unwrap_err().kind(), ErrorQueue::Other);
+
+    // Позиция прежнего формата без контрольной суммы
+    fs::write(&file_name, format!("{};checkpoint_consumer;{};2;{}\n", queue_name, (HEADER_SIZE + 1) * 2, queue.id)).unwrap();
+    consumer.get_info().unwrap();
+    assert_eq!(consumer.count_popped, 2);
+    assert_eq!(consumer.peek().unwrap().unwrap().as_str(), Some("2"));
+
+    // Консьюмер с повреждёнными позициями не открывается и не начинает с текущей части
+    drop(consumer);
+    let corrupted = info.replace(";5;", ";4;");
+    fs::write(&file_name, &corrupted).unwrap();
+    fs::write(&prev_file_name, "garbage").unwrap();
+    let consumer = Consumer::new(&base_path, "checkpoint_consumer", queue_name);
+    assert_eq!(consumer.err().unwrap().kind(), ErrorQueue::Other);
+    assert_eq!(fs::read_to_string(&file_name).unwrap(), corrupted);
+    assert_eq!(fs::read_to_string(&prev_file_name).unwrap(), "garbage");
+}
+
+#[test]
+fn test_read_mode_consumer() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..5 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    let mut consumer = Consumer::new(&base_path, "shared_consumer", queue_name).unwrap();
+    for _ in 0..2 {
+        consumer.next_message().unwrap().unwrap();
+    }
+    consumer.commit().unwrap();
+    let info = fs::read_to_string(consumer.info_pop_file_name()).unwrap();
+
+    // Потребитель в режиме чтения читает с позиции пишущего, но не заменяет его позицию
+    let mut reader = Consumer::new_with_mode(&base_path, "shared_consumer", queue_name, Mode::Read).unwrap();
+    let mut received = Vec::new();
+    while let Some(message) = reader.next_message().unwrap() {
+        received.push(message.as_str().unwrap().to_owned());
+    }
+    assert_eq!(received, vec!["2", "3", "4"]);
+    assert_eq!(reader.commit().unwrap_err().kind(), ErrorQueue::NotReady);
+
+    // Переход в следующую часть тоже не записывает позицию
+    drop(queue);
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    queue.push(b"5", MsgType::String).unwrap();
+    assert_eq!(reader.next_message().unwrap().unwrap().as_str(), Some("5"));
+    assert_eq!(reader.id, queue.id);
+
+    assert_eq!(fs::read_to_string(consumer.info_pop_file_name()).unwrap(), info);
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("2"));
+}
+
+#[test]
+fn test_info_checksum() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    let mut right_edge = 0;
+    for i in 0..3 {
+        right_edge = queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    // Строка с неверной контрольной суммой обнаруживается при чтении
+    let info_push = queue.info_push_file_name(queue.id);
+    fs::write(&info_push, format!("{};{};2;12345\n", queue_name, right_edge)).unwrap();
+    let reader = Queue::new(&base_path, queue_name, Mode::Read).unwrap();
+    assert_eq!(reader.read_info_of_part(queue.id).unwrap_err().kind(), ErrorQueue::InvalidInfoChecksum);
+
+    // Пишущий восстанавливает информацию части по её записям
+    queue.open_part(queue.id).unwrap();
+    assert_eq!(reader.read_info_of_part(queue.id).unwrap(), (right_edge, 3));
+    queue.push(b"3", MsgType::String).unwrap();
+    assert_eq!(queue.get(4).unwrap().as_str(), Some("3"));
+
+    // Номер текущей части восстанавливается по каталогам частей
+    let last_id = queue.id;
+    drop(queue);
+    fs::write(base_path.to_owned() + "/" + queue_name + "_info_queue", format!("{};7;12345\n", queue_name)).unwrap();
+    let mut reader = Queue::new(&base_path, queue_name, Mode::Read).unwrap();
+    assert_eq!(reader.read_info_queue().unwrap_err().kind(), ErrorQueue::InvalidInfoChecksum);
+
+    let queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    assert_eq!(queue.id, last_id + 1);
+    reader.read_info_queue().unwrap();
+    assert_eq!(reader.id, last_id + 1);
+
+    // Испорченная информация закрытой части восстанавливается пишущим при открытии очереди
+    let info = reader.read_info_of_part(last_id).unwrap();
+    drop(queue);
+    fs::write(&info_push, format!("{};{};1;12345\n", queue_name, info.0)).unwrap();
+    assert_eq!(reader.read_info_of_part(last_id).unwrap_err().kind(), ErrorQueue::InvalidInfoChecksum);
+
+    let _queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    assert_eq!(reader.read_info_of_part(last_id).unwrap(), info);
+
+    let mut consumer = Consumer::new(&base_path, "consumer", queue_name).unwrap();
+    consumer.seek_to_part(last_id).unwrap();
+    for i in 0..4 {
+        assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some(i.to_string().as_str()));
+    }
+}
+
+#[test]
+fn test_consumer_restart() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+
+    // Позиция сохраняется для очередей с любыми именами, в том числе individuals-flow
+    for queue_name in ["orders", "queue.v2", "events-2026", "individuals-flow"] {
+        let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+        for i in 0..6 {
+            queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+        }
+
+        let mut consumer = Consumer::new(&base_path, "restart_consumer", queue_name).unwrap();
+        for _ in 0..2 {
+            consumer.next_message().unwrap().unwrap();
+        }
+        consumer.commit().unwrap();
+        drop(consumer);
+
+        let mut consumer = Consumer::new(&base_path, "restart_consumer", queue_name).unwrap();
+        assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("2"));
+        consumer.commit().unwrap();
+        drop(consumer);
+
+        // Перезапуск пишущего открывает новую часть, потребитель продолжает с прежней
+        drop(queue);
+        let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+        queue.push(b"6", MsgType::String).unwrap();
+
+        let mut consumer = Consumer::new(&base_path, "restart_consumer", queue_name).unwrap();
+        let mut received = Vec::new();
+        while let Some(message) = consumer.next_message().unwrap() {
+            received.push(message.as_str().unwrap().parse::<i32>().unwrap());
+        }
+        assert_eq!(received, vec![3, 4, 5, 6]);
+        consumer.commit().unwrap();
+
+        let registry = ConsumerRegistry::new(&base_path, queue_name).unwrap();
+        assert_eq!(registry.list().unwrap().len(), 1);
+
+        // Файл individuals-flow не создаётся для потребителей других очередей
+        let legacy_exists = std::path::Path::new(&(base_path.to_owned() + "/" + LEGACY_INFO_POP_PREFIX + "restart_consumer")).exists();
+        assert_eq!(legacy_exists, queue_name == "individuals-flow");
+    }
+
+    let consumer = Consumer::new_with_mode(&base_path, "unknown_consumer", "orders", Mode::Read);
+    assert_eq!(consumer.err().unwrap().kind(), ErrorQueue::NotReady);
+}
+
+#[test]
+fn test_legacy_checkpoint_migration() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "orders";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..5 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    // Позиция, записанная прежними версиями в файл individuals-flow
+    let legacy_file_name = base_path.to_owned() + "/" + LEGACY_INFO_POP_PREFIX + "legacy_consumer";
+    fs::write(&legacy_file_name, format!("{};legacy_consumer;{};3;{}\n", queue_name, (HEADER_SIZE + 1) * 3, queue.id)).unwrap();
+
+    // Позиция потребителя другой очереди с тем же именем не переносится
+    let mut other = Queue::new(&base_path, "payments", Mode::ReadWrite).unwrap();
+    other.push(b"0", MsgType::String).unwrap();
+    let consumer = Consumer::new(&base_path, "legacy_consumer", "payments").unwrap();
+    assert_eq!(consumer.count_popped, 0);
+    drop(consumer);
+    assert!(std::path::Path::new(&legacy_file_name).exists());
+
+    let mut consumer = Consumer::new(&base_path, "legacy_consumer", queue_name).unwrap();
+    assert_eq!(consumer.count_popped, 3);
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("3"));
+    assert!(!std::path::Path::new(&legacy_file_name).exists());
+
+    let info = fs::read_to_string(consumer.info_pop_file_name()).unwrap();
+    assert!(info.starts_with(&format!("{};legacy_consumer;{};3;", queue_name, (HEADER_SIZE + 1) * 3)));
+}
+
+#[test]
+fn test_ephemeral_consumer() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..5 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    let read_all = |consumer: &mut Consumer| {
+        let mut received = Vec::new();
+        while let Some(message) = consumer.next_message().unwrap() {
+            received.push(message.as_str().unwrap().to_owned());
+        }
+        received
+    };
+
+    // Несколько эфемерных потребителей читают очередь с разных позиций
+    let mut head = Consumer::ephemeral(&base_path, queue_name, StartPosition::Head).unwrap();
+    let mut tail = Consumer::ephemeral(&base_path, queue_name, StartPosition::Tail).unwrap();
+    let mut from_seq = Consumer::ephemeral(&base_path, queue_name, StartPosition::Sequence(3)).unwrap();
+    assert!(head.is_ephemeral());
+
+    assert_eq!(read_all(&mut head), vec!["0", "1", "2", "3", "4"]);
+    assert!(read_all(&mut tail).is_empty());
+    assert_eq!(read_all(&mut from_seq), vec!["2", "3", "4"]);
+
+    queue.push(b"5", MsgType::String).unwrap();
+    assert_eq!(read_all(&mut tail), vec!["5"]);
+    assert_eq!(read_all(&mut head), vec!["5"]);
+
+    // Позиция не сохраняется: файлы потребителя не создаются
+    head.set_commit_policy(CommitPolicy::EveryMessage);
+    head.commit().unwrap();
+    drop(head);
+    let files: Vec<String> = fs::read_dir(&base_path).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+    assert!(!files.iter().any(|name| name.contains("_info_pop_")), "{:?}", files);
+    assert!(ConsumerRegistry::new(&base_path, queue_name).unwrap().list().unwrap().is_empty());
+
+    let mut head = Consumer::ephemeral(&base_path, queue_name, StartPosition::Head).unwrap();
+    assert_eq!(head.next_message().unwrap().unwrap().as_str(), Some("0"));
+    assert_eq!(head.get_info().unwrap_err().kind(), ErrorQueue::NotFound);
+
+    // Номер записи вне части
+    let res = Consumer::ephemeral(&base_path, queue_name, StartPosition::Sequence(100));
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_consumer_filter() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for i in 0..6 {
+        let msg_type = if i % 2 == 0 { MsgType::Object } else { MsgType::String };
+        queue.push(format!("{}", i).repeat(i + 1).as_bytes(), msg_type).unwrap();
+    }
+
+    // Только записи типа Object, остальные пропускаются, но учитываются в позиции
+    let mut consumer = Consumer::new(&base_path, "objects", queue_name).unwrap().with_filter(MessageFilter::types(&[MsgType::Object]));
+    assert_eq!(consumer.peek().unwrap().unwrap().as_str(), Some("0"));
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("0"));
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("222"));
+    consumer.commit().unwrap();
+    assert_eq!(consumer.count_popped, 3);
+    drop(consumer);
+
+    let mut consumer = Consumer::new(&base_path, "objects", queue_name).unwrap().with_filter(MessageFilter::types(&[MsgType::Object]));
+    let message = consumer.next_message().unwrap().unwrap();
+    assert_eq!(message.as_str(), Some("44444"));
+    assert_eq!(message.id().seq, 5);
+    assert!(consumer.next_message().unwrap().is_none());
+    assert_eq!(consumer.count_popped, 6);
+
+    // Свойства заголовка и замыкание
+    let filter = MessageFilter::properties(&[Property::MsgType(MsgType::String), Property::MinLength(3)]);
+    let mut consumer = Consumer::ephemeral(&base_path, queue_name, StartPosition::Head).unwrap().with_filter(filter);
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("3333"));
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("555555"));
+    assert!(consumer.next_message().unwrap().is_none());
+
+    let mut consumer = Consumer::ephemeral(&base_path, queue_name, StartPosition::Head).unwrap();
+    consumer.set_mmap(true);
+    consumer.set_filter(Some(MessageFilter::predicate(|header| header.count_pushed % 3 == 0)));
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("222"));
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("555555"));
+    assert!(consumer.next_message().unwrap().is_none());
+}
+
+#[test]
+fn test_msg_types() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let types = [
+        MsgType::String,
+        MsgType::Object,
+        MsgType::Binary,
+        MsgType::Json,
+        MsgType::Cbor,
+        MsgType::Tombstone,
+        MsgType::User(USER_MSG_TYPE_MIN),
+        MsgType::User(255),
+    ];
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    for t in types {
+        queue.push(format!("{:?}", t).as_bytes(), t).unwrap();
+    }
+
+    // Неизвестные типы не записываются
+    assert_eq!(queue.push(b"x", MsgType::User(1)).unwrap_err().kind(), ErrorQueue::UnknownMsgType);
+    assert_eq!(queue.push(b"x", MsgType::Unknown(b'X')).unwrap_err().kind(), ErrorQueue::UnknownMsgType);
+
+    // Запись более новой версии с неизвестным типом
+    let pos = queue.right_edge;
+    queue.push(b"newer", MsgType::String).unwrap();
+    let part_file_name = queue.part_file_name(queue.id);
+    let mut content = fs::read(&part_file_name).unwrap();
+    let start = pos as usize;
+    content[start + 20] = b'X';
+    content[start + 21..start + HEADER_SIZE].fill(0);
+    let crc = crc32fast::hash(&content[start..start + HEADER_SIZE + 5]);
+    content[start + 21..start + HEADER_SIZE].copy_from_slice(&crc.to_ne_bytes());
+    fs::write(&part_file_name, content).unwrap();
+
+    // Типы читаются без изменений, неизвестный тип не превращается в String
+    let mut consumer = Consumer::new(&base_path, "types_consumer", queue_name).unwrap();
+    for t in types {
+        let message = consumer.next_message().unwrap().unwrap();
+        assert_eq!(message.msg_type, t);
+        assert_eq!(message.as_str().unwrap(), format!("{:?}", t));
+    }
+    let message = consumer.next_message().unwrap().unwrap();
+    assert_eq!(message.msg_type, MsgType::Unknown(b'X'));
+    assert_eq!(message.as_str(), Some("newer"));
+    assert!(!message.msg_type.is_valid());
+
+    // Тип сохраняется в мёртвых письмах
+    for t in [MsgType::Json, MsgType::User(200), MsgType::Unknown(b'1')] {
+        let letter = DeadLetter {
+            queue_name: queue_name.to_owned(),
+            part_id: 1,
+            pos: 2,
+            count_pushed: 3,
+            msg_type: t,
+            reason: "test".to_owned(),
+            data: b"body".to_vec(),
+        };
+        assert_eq!(DeadLetter::from_bytes(&letter.to_bytes()), Some(letter));
+    }
+}
+
+#[cfg(feature = "schema")]
+#[test]
+fn test_schema_registry() {
+    use crate::schema::SchemaRegistry;
+
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    let mut registry = SchemaRegistry::open(&base_path, queue_name).unwrap();
+
+    // Версии схемы, повторная регистрация того же документа не создаёт версию
+    let user_v1 = registry.register("user", r#"{"type": "object", "required": ["name"], "properties": {"name": {"type": "string", "minLength": 1}}}"#).unwrap();
+    let same = registry.register("user", r#"{"required": ["name"], "type": "object", "properties": {"name": {"minLength": 1, "type": "string"}}}"#);
+    assert_eq!(same.unwrap(), user_v1);
+    let user_v2 = registry
+        .register(
+            "user",
+            r#"{"type": "object", "required": ["name", "age"], "properties": {"name": {"type": "string"}, "age": {"type": "integer", "minimum": 0}}, "additionalProperties": false}"#,
+        )
+        .unwrap();
+    assert_ne!(user_v1, user_v2);
+    assert_eq!(registry.latest("user").unwrap().version, 2);
+    assert_eq!(registry.register("user", "not json").unwrap_err().kind(), ErrorQueue::InvalidSchema);
+
+    // Схемы с ключевыми словами, которые не проверяются, не регистрируются
+    let unsupported = [
+        r#"{"type": "string", "pattern": "^a"}"#,
+        r#"{"properties": {"email": {"format": "email"}}}"#,
+        r#"{"items": [{"type": "string"}]}"#,
+        r#"{"$ref": "other.json"}"#,
+    ];
+    for document in unsupported {
+        assert_eq!(registry.register("unsupported", document).unwrap_err().kind(), ErrorQueue::InvalidSchema);
+    }
+    assert!(registry.latest("unsupported").is_none());
+    registry.register("annotated", r#"{"title": "Name", "description": "Name of the user", "type": ["string", "null"]}"#).unwrap();
+
+    // Реестр читается другим процессом
+    let other = SchemaRegistry::open(&base_path, queue_name).unwrap();
+    assert_eq!(other.versions("user").len(), 2);
+    assert_eq!(other.get(user_v2).unwrap().name, "user");
+
+    queue.set_schema_registry(registry);
+    queue.set_strict_schema(true);
+
+    // В строгом режиме записи без схемы и с неверным содержимым не записываются
+    assert_eq!(queue.push(br#"{"name": "a"}"#, MsgType::Json).unwrap_err().kind(), ErrorQueue::InvalidSchema);
+    queue.set_schema(Some(user_v1)).unwrap();
+    queue.push(br#"{"name": "a"}"#, MsgType::Json).unwrap();
+    assert_eq!(queue.push(br#"{"name": ""}"#, MsgType::Json).unwrap_err().kind(), ErrorQueue::InvalidSchema);
+    assert_eq!(queue.push(b"not json", MsgType::Json).unwrap_err().kind(), ErrorQueue::InvalidSchema);
+    queue.push_with_schema(br#"{"name": "b", "age": 3}"#, MsgType::Json, user_v2).unwrap();
+    let err = queue.push_with_schema(br#"{"name": "c", "age": 3, "x": 1}"#, MsgType::Json, user_v2).unwrap_err();
+    assert_eq!(err.kind(), ErrorQueue::InvalidSchema);
+    assert_eq!(queue.set_schema(Some(100)).unwrap_err().kind(), ErrorQueue::NotFound);
+
+    // Без строгого режима записи не проверяются
+    queue.set_strict_schema(false);
+    queue.set_schema(None).unwrap();
+    queue.push(b"untagged", MsgType::String).unwrap();
+    queue.push_with_schema(br#"{"name": ""}"#, MsgType::Json, user_v1).unwrap();
+
+    // Потребитель получает номер схемы вместе с записью
+    let mut consumer = Consumer::new(&base_path, "schema_consumer", queue_name).unwrap();
+    assert_eq!(consumer.peek().unwrap().unwrap().schema_id, Some(user_v1));
+    let ids: Vec<Option<u32>> = consumer.messages().map(|m| m.unwrap().schema_id).collect();
+    assert_eq!(ids, vec![Some(user_v1), Some(user_v2), None, Some(user_v1)]);
+
+    consumer.seek_to_part(queue.id).unwrap();
+    consumer.pop_header().unwrap().unwrap();
+    assert_eq!(consumer.schema_id(), Some(user_v1));
+
+    // Запись, номер схемы которой не записан, удаляется из части
+    drop(queue);
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    queue.set_schema_registry(SchemaRegistry::open(&base_path, queue_name).unwrap());
+    fs::create_dir(queue.schema_ids_file_name(queue.id)).unwrap();
+    queue.set_schema(Some(user_v1)).unwrap();
+    assert_eq!(queue.push(br#"{"name": "d"}"#, MsgType::Json).unwrap_err().kind(), ErrorQueue::FailOpen);
+    queue.set_schema(None).unwrap();
+    queue.push(b"after", MsgType::String).unwrap();
+    assert_eq!(fs::metadata(queue.part_file_name(queue.id)).unwrap().len(), queue.right_edge);
+    assert_eq!(queue.get(1).unwrap().as_str(), Some("after"));
+
+    // Потребитель не запоминает номер схемы записи, которая ещё не опубликована
+    fs::remove_dir(queue.schema_ids_file_name(queue.id)).unwrap();
+    let mut reader = Consumer::ephemeral(&base_path, queue_name, StartPosition::Tail).unwrap();
+    queue.push_with_schema(br#"{"name": "f"}"#, MsgType::Json, user_v1).unwrap();
+    queue.begin().unwrap();
+    queue.push_with_schema(br#"{"name": "g"}"#, MsgType::Json, user_v2).unwrap();
+    assert_eq!(reader.next_message().unwrap().unwrap().schema_id, Some(user_v1));
+    queue.abort().unwrap();
+    queue.push(b"h", MsgType::String).unwrap();
+    let message = reader.next_message().unwrap().unwrap();
+    assert_eq!(message.as_str(), Some("h"));
+    assert_eq!(message.schema_id, None);
+
+    // Записи через QueueProducer проверяются и помечаются так же, как push
+    queue.set_strict_schema(true);
+    queue.set_schema(Some(user_v1)).unwrap();
+    let mut tail = Consumer::ephemeral(&base_path, queue_name, StartPosition::Tail).unwrap();
+    let producer = QueueProducer::new(queue).unwrap();
+    assert_eq!(producer.push(b"not json", MsgType::Json).wait().unwrap_err().kind(), ErrorQueue::InvalidSchema);
+    producer.push(br#"{"name": "e"}"#, MsgType::Json).wait().unwrap();
+    let message = tail.next_message().unwrap().unwrap();
+    assert_eq!(message.as_str(), Some(r#"{"name": "e"}"#));
+    assert_eq!(message.schema_id, Some(user_v1));
+    assert!(tail.next_message().unwrap().is_none());
+}
+
+#[test]
+fn test_queue_transaction() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+
+    let read_all = |consumer: &mut Consumer| {
+        let mut received = Vec::new();
+        while let Some(message) = consumer.next_message().unwrap() {
+            received.push(message.as_str().unwrap().to_owned());
+        }
+        received
+    };
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    queue.push(b"0", MsgType::String).unwrap();
+    let mut consumer = Consumer::new(&base_path, "tx_consumer", queue_name).unwrap();
+    assert_eq!(read_all(&mut consumer), vec!["0"]);
+
+    // Сообщения транзакции не видны до фиксации
+    queue.begin().unwrap();
+    assert_eq!(queue.begin().unwrap_err().kind(), ErrorQueue::AlreadyOpen);
+    queue.push(b"1", MsgType::String).unwrap();
+    queue.push(b"2", MsgType::String).unwrap();
+    assert!(read_all(&mut consumer).is_empty());
+    assert!(consumer.peek().unwrap().is_none());
+    queue.commit().unwrap();
+    assert_eq!(read_all(&mut consumer), vec!["1", "2"]);
+
+    // Отменённая транзакция не читается, следующие записи пишутся на её место
+    queue.begin().unwrap();
+    queue.push(b"aborted 1", MsgType::String).unwrap();
+    queue.push(b"aborted 2", MsgType::String).unwrap();
+    queue.abort().unwrap();
+    assert_eq!(queue.commit().unwrap_err().kind(), ErrorQueue::NotReady);
+    queue.push(b"3", MsgType::String).unwrap();
+    assert_eq!(read_all(&mut consumer), vec!["3"]);
+    assert_eq!(queue.get(4).unwrap().as_str(), Some("3"));
+
+    // Сбой пишущего посреди транзакции
+    queue.begin().unwrap();
+    queue.push(b"lost 1", MsgType::String).unwrap();
+    queue.push(b"lost 2", MsgType::String).unwrap();
+    assert!(queue.in_transaction());
+    drop(queue);
+    assert!(read_all(&mut consumer).is_empty());
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    queue.push(b"4", MsgType::String).unwrap();
+    assert_eq!(read_all(&mut consumer), vec!["4"]);
+    consumer.commit().unwrap();
+
+    let mut consumer = Consumer::ephemeral(&base_path, queue_name, StartPosition::Head).unwrap();
+    assert_eq!(read_all(&mut consumer), vec!["0", "1", "2", "3", "4"]);
+}
+
+#[test]
+fn test_transaction_abort_index() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+    let queue_name = "test_queue";
+    let interval = Duration::from_millis(20);
+
+    let mut queue = Queue::new(&base_path, queue_name, Mode::ReadWrite).unwrap();
+    queue.set_time_index_interval(interval);
+    for i in 0..60 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    // Отменённая транзакция пересекает границу интервала индекса
+    thread::sleep(interval * 2);
+    queue.begin().unwrap();
+    for i in 60..64 {
+        queue.push(format!("aborted record {}", i).as_bytes(), MsgType::String).unwrap();
+    }
+    thread::sleep(interval * 2);
+    let t = SystemTime::now();
+    thread::sleep(interval * 2);
+    for i in 64..80 {
+        queue.push(format!("aborted record {}", i).as_bytes(), MsgType::String).unwrap();
+    }
+    queue.abort().unwrap();
+
+    // Индексы указывают на записи, записанные на место отменённых
+    thread::sleep(interval * 2);
+    for i in 60..80 {
+        queue.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+    assert_eq!(queue.get(65).unwrap().as_str(), Some("64"));
+    assert_eq!(queue.get(70).unwrap().as_str(), Some("69"));
+    let index = PartIndex::read(&queue, queue.id).unwrap();
+    assert_eq!(index.entries.len(), 2);
+
+    let mut consumer = Consumer::new(&base_path, "index_consumer", queue_name).unwrap();
+    consumer.seek_to_sequence(70).unwrap();
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("69"));
+
+    consumer.seek_to_time(t).unwrap();
+    assert_eq!(consumer.next_message().unwrap().unwrap().as_str(), Some("60"));
+    let time_index = PartTimeIndex::read(&queue, queue.id).unwrap();
+    assert!(time_index.entries.iter().all(|e| queue.get(e.seq).unwrap().pos == e.pos));
+}
+
+#[test]
+fn test_bridge() {
+    let base_path = create_unique_queue_path("./test-tmp", "queue");
+
+    let mut input = Queue::new(&base_path, "input", Mode::ReadWrite).unwrap();
+    for i in 0..8 {
+        input.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    let transform = |message: &Message| {
+        let n = message.as_str().unwrap();
+        vec![(format!("{}a", n).into_bytes(), MsgType::String), (format!("{}b", n).into_bytes(), MsgType::String)]
+    };
+
+    let mut bridge = Bridge::new("stage", &base_path, "input", &base_path, "output").unwrap();
+    assert_eq!(bridge.process(2, transform).unwrap(), 2);
+
+    // Сбой после публикации выходных сообщений до фиксации позиции входа
+    let (count, state) = bridge.prepare(2, transform).unwrap();
+    assert_eq!(count, 2);
+    bridge.output.commit().unwrap();
+    drop(bridge);
+    let registry = ConsumerRegistry::new(&base_path, "input").unwrap();
+    assert_eq!(registry.get("stage").unwrap().count_popped, 2);
+
+    let mut bridge = Bridge::new("stage", &base_path, "input", &base_path, "output").unwrap();
+    assert_eq!(registry.get("stage").unwrap().count_popped, state.unwrap().in_count);
+
+    // Сбой до публикации выходных сообщений
+    bridge.prepare(3, transform).unwrap();
+    drop(bridge);
+
+    let mut bridge = Bridge::new("stage", &base_path, "input", &base_path, "output").unwrap();
+    while bridge.process(3, transform).unwrap() > 0 {}
+    assert_eq!(bridge.process(3, transform).unwrap(), 0);
+    drop(bridge);
+
+    // Каждое входное сообщение дало свои выходные сообщения один раз
+    let mut output = Consumer::ephemeral(&base_path, "output", StartPosition::Head).unwrap();
+    let mut received = Vec::new();
+    while let Some(message) = output.next_message().unwrap() {
+        received.push(message.as_str().unwrap().to_owned());
+    }
+    let expected: Vec<String> = (0..8).flat_map(|i| [format!("{}a", i), format!("{}b", i)]).collect();
+    assert_eq!(received, expected);
+
+    // Пакет захватывает две части входа, сбой до публикации выходных сообщений:
+    // позиция входа не сохраняется при переходе на следующую часть
+    for i in 8..10 {
+        input.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+    let in_part = input.id;
+    drop(input);
+    let mut input = Queue::new(&base_path, "input", Mode::ReadWrite).unwrap();
+    for i in 10..12 {
+        input.push(format!("{}", i).as_bytes(), MsgType::String).unwrap();
+    }
+
+    let mut bridge = Bridge::new("stage", &base_path, "input", &base_path, "output").unwrap();
+    let (count, state) = bridge.prepare(4, transform).unwrap();
+    assert_eq!((count, state.unwrap().in_part), (4, input.id));
+    drop(bridge);
+    let checkpoint = registry.get("stage").unwrap();
+    assert_eq!((checkpoint.part_id, checkpoint.count_popped), (in_part, 8));
+
+    let mut bridge = Bridge::new("stage", &base_path, "input", &base_path, "output").unwrap();
+    assert_eq!(bridge.process(4, transform).unwrap(), 4);
+    drop(bridge);
+
+    let mut output = Consumer::ephemeral(&base_path, "output", StartPosition::Head).unwrap();
+    let mut received = Vec::new();
+    while let Some(message) = output.next_message().unwrap() {
+        received.push(message.as_str().unwrap().to_owned());
+    }
+    let expected: Vec<String> = (0..12).flat_map(|i| [format!("{}a", i), format!("{}b", i)]).collect();
+    assert_eq!(received, expected);
+}
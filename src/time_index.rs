@@ -0,0 +1,255 @@
+use crate::error::{Error, Result};
+use crate::queue::Queue;
+use crate::record::*;
+use std::fs::*;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const TIME_INDEX_INTERVAL: Duration = Duration::from_millis(1000);
+
+// <min ts><max ts><interval>, then entries <ts><pos><seq>, native endian as in the record header.
+// Timestamps are milliseconds since the unix epoch, one entry is written for the first record of every interval,
+// max ts is the end of the interval of the last entry
+const TIME_INDEX_HEADER_SIZE: u64 = 24;
+const TIME_INDEX_ENTRY_SIZE: u64 = 20;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct TimeIndexEntry {
+    pub ts: u64,
+    pub pos: u64,
+    pub seq: u32,
+}
+
+impl TimeIndexEntry {
+    fn from_buf(buf: &[u8]) -> TimeIndexEntry {
+        TimeIndexEntry {
+            ts: u64::from_ne_bytes(buf[0..8].try_into().unwrap_or_default()),
+            pos: u64::from_ne_bytes(buf[8..16].try_into().unwrap_or_default()),
+            seq: u32::from_ne_bytes(buf[16..20].try_into().unwrap_or_default()),
+        }
+    }
+
+    fn to_buf(self) -> [u8; TIME_INDEX_ENTRY_SIZE as usize] {
+        let mut buf = [0; TIME_INDEX_ENTRY_SIZE as usize];
+        buf[0..8].copy_from_slice(&self.ts.to_ne_bytes());
+        buf[8..16].copy_from_slice(&self.pos.to_ne_bytes());
+        buf[16..20].copy_from_slice(&self.seq.to_ne_bytes());
+        buf
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PartTimeIndex {
+    pub part_id: u32,
+    pub min_ts: u64,
+    pub max_ts: u64,
+    pub interval_ms: u64,
+    pub entries: Vec<TimeIndexEntry>,
+}
+
+impl PartTimeIndex {
+    pub fn read(queue: &Queue, part_id: u32) -> Result<PartTimeIndex> {
+        let file_name = queue.time_index_file_name(part_id);
+
+        let mut buf = Vec::new();
+        if let Err(e) = File::open(&file_name).and_then(|mut f| f.read_to_end(&mut buf)) {
+            return Err(Error::io(ErrorQueue::NotFound, "read time index", file_name, e).with_part(part_id));
+        }
+
+        if buf.len() < TIME_INDEX_HEADER_SIZE as usize {
+            return Err(Error::new(ErrorQueue::Other, "read time index, invalid format").with_path(file_name).with_part(part_id));
+        }
+
+        let mut index = PartTimeIndex {
+            part_id,
+            min_ts: u64::from_ne_bytes(buf[0..8].try_into().unwrap_or_default()),
+            max_ts: u64::from_ne_bytes(buf[8..16].try_into().unwrap_or_default()),
+            interval_ms: u64::from_ne_bytes(buf[16..24].try_into().unwrap_or_default()).max(1),
+            entries: buf[TIME_INDEX_HEADER_SIZE as usize..].chunks_exact(TIME_INDEX_ENTRY_SIZE as usize).map(TimeIndexEntry::from_buf).collect(),
+        };
+
+        // the header is written after the entry, it may be behind the entries after a crash of the writer
+        if let (Some(first), Some(last)) = (index.entries.first(), index.entries.last()) {
+            index.min_ts = first.ts;
+            index.max_ts = index.max_ts.max(interval_end(last.ts, index.interval_ms));
+        }
+
+        Ok(index)
+    }
+
+    // Reads min and max timestamps of the part without reading all entries, None if the index is empty
+    pub fn read_bounds(queue: &Queue, part_id: u32) -> Result<Option<(u64, u64)>> {
+        let file_name = queue.time_index_file_name(part_id);
+
+        let mut header = [0; TIME_INDEX_HEADER_SIZE as usize];
+        let mut first = [0; TIME_INDEX_ENTRY_SIZE as usize];
+        let mut last = [0; TIME_INDEX_ENTRY_SIZE as usize];
+
+        let res = File::open(&file_name).and_then(|mut f| {
+            let count = f.metadata()?.len().saturating_sub(TIME_INDEX_HEADER_SIZE) / TIME_INDEX_ENTRY_SIZE;
+            if count == 0 {
+                return Ok(0);
+            }
+            f.read_exact(&mut header)?;
+            f.read_exact(&mut first)?;
+            f.seek(SeekFrom::Start(TIME_INDEX_HEADER_SIZE + (count - 1) * TIME_INDEX_ENTRY_SIZE))?;
+            f.read_exact(&mut last)?;
+            Ok(count)
+        });
+
+        match res {
+            Ok(0) => Ok(None),
+            Ok(_) => {
+                let max_ts = u64::from_ne_bytes(header[8..16].try_into().unwrap_or_default());
+                let interval_ms = u64::from_ne_bytes(header[16..24].try_into().unwrap_or_default()).max(1);
+                let last_end = interval_end(TimeIndexEntry::from_buf(&last).ts, interval_ms);
+                Ok(Some((TimeIndexEntry::from_buf(&first).ts, max_ts.max(last_end))))
+            },
+            Err(e) => Err(Error::io(ErrorQueue::NotFound, "read time index", file_name, e).with_part(part_id)),
+        }
+    }
+
+    // Returns the first entry of the interval that contains ts, or the first entry after it
+    pub fn find(&self, ts: u64) -> Option<&TimeIndexEntry> {
+        let idx = self.entries.partition_point(|e| interval_end(e.ts, self.interval_ms) < ts);
+        self.entries.get(idx)
+    }
+}
+
+pub fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+fn interval_end(ts: u64, interval_ms: u64) -> u64 {
+    (ts / interval_ms + 1) * interval_ms - 1
+}
+
+// Time index of the part being written
+pub(crate) struct TimeIndexWriter {
+    file: File,
+    file_name: String,
+    interval_ms: u64,
+    min_ts: u64,
+    last: Option<TimeIndexEntry>,
+}
+
+impl TimeIndexWriter {
+    pub(crate) fn open(queue: &Queue, part_id: u32, interval: Duration) -> Result<TimeIndexWriter> {
+        let file_name = queue.time_index_file_name(part_id);
+
+        let mut file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&file_name) {
+            Ok(f) => f,
+            Err(e) => return Err(Error::io(ErrorQueue::FailOpen, "open time index", file_name, e).with_part(part_id)),
+        };
+
+        let mut buf = Vec::new();
+        if let Err(e) = file.read_to_end(&mut buf) {
+            return Err(Error::io(ErrorQueue::FailRead, "open time index", file_name, e).with_part(part_id));
+        }
+
+        let mut writer = TimeIndexWriter {
+            file,
+            file_name,
+            interval_ms: (interval.as_millis() as u64).max(1),
+            min_ts: 0,
+            last: None,
+        };
+
+        if buf.len() < TIME_INDEX_HEADER_SIZE as usize {
+            writer.write_header()?;
+            return Ok(writer);
+        }
+
+        // the interval of an existing index is kept, an incomplete last entry is dropped
+        writer.interval_ms = u64::from_ne_bytes(buf[16..24].try_into().unwrap_or_default()).max(1);
+        let count = (buf.len() as u64 - TIME_INDEX_HEADER_SIZE) / TIME_INDEX_ENTRY_SIZE;
+        let len = TIME_INDEX_HEADER_SIZE + count * TIME_INDEX_ENTRY_SIZE;
+        if count > 0 {
+            let first = TIME_INDEX_HEADER_SIZE as usize;
+            let last = (len - TIME_INDEX_ENTRY_SIZE) as usize;
+            writer.min_ts = TimeIndexEntry::from_buf(&buf[first..]).ts;
+            writer.last = Some(TimeIndexEntry::from_buf(&buf[last..]));
+        }
+        if len != buf.len() as u64 {
+            if let Err(e) = writer.file.set_len(len) {
+                return Err(Error::io(ErrorQueue::FailWrite, "open time index", writer.file_name, e).with_part(part_id));
+            }
+        }
+
+        Ok(writer)
+    }
+
+    // Sets the interval of an index without entries
+    pub(crate) fn set_interval(&mut self, interval: Duration) -> Result<()> {
+        if self.last.is_none() {
+            self.interval_ms = (interval.as_millis() as u64).max(1);
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    // Drops the entries of the records after count_pushed, the records were dropped from the part
+    pub(crate) fn truncate(&mut self, count_pushed: u32) -> Result<()> {
+        let mut buf = Vec::new();
+        if let Err(e) = self.file.seek(SeekFrom::Start(0)).and_then(|_| self.file.read_to_end(&mut buf)) {
+            return Err(Error::io(ErrorQueue::FailRead, "truncate time index", &self.file_name, e));
+        }
+
+        let entries: Vec<TimeIndexEntry> = buf
+            .get(TIME_INDEX_HEADER_SIZE as usize..)
+            .unwrap_or_default()
+            .chunks_exact(TIME_INDEX_ENTRY_SIZE as usize)
+            .map(TimeIndexEntry::from_buf)
+            .take_while(|e| e.seq <= count_pushed)
+            .collect();
+
+        let len = TIME_INDEX_HEADER_SIZE + entries.len() as u64 * TIME_INDEX_ENTRY_SIZE;
+        if let Err(e) = self.file.set_len(len) {
+            return Err(Error::io(ErrorQueue::FailWrite, "truncate time index", &self.file_name, e));
+        }
+
+        self.min_ts = entries.first().map_or(0, |e| e.ts);
+        self.last = entries.last().copied();
+        self.write_header()
+    }
+
+    pub(crate) fn add(&mut self, ts: u64, pos: u64, seq: u32) -> Result<()> {
+        // the entries are kept ordered if the clock goes back
+        let ts = self.last.map_or(ts, |l| ts.max(l.ts));
+        if self.last.is_some_and(|l| ts / self.interval_ms == l.ts / self.interval_ms) {
+            return Ok(());
+        }
+
+        let entry = TimeIndexEntry {
+            ts,
+            pos,
+            seq,
+        };
+
+        if let Err(e) = self.file.seek(SeekFrom::End(0)).and_then(|_| self.file.write_all(&entry.to_buf())) {
+            return Err(Error::io(ErrorQueue::FailWrite, "add time index entry", &self.file_name, e));
+        }
+
+        if self.last.is_none() {
+            self.min_ts = ts;
+        }
+        self.last = Some(entry);
+
+        self.write_header()
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let max_ts = self.last.map_or(0, |l| interval_end(l.ts, self.interval_ms));
+
+        let mut buf = [0; TIME_INDEX_HEADER_SIZE as usize];
+        buf[0..8].copy_from_slice(&self.min_ts.to_ne_bytes());
+        buf[8..16].copy_from_slice(&max_ts.to_ne_bytes());
+        buf[16..24].copy_from_slice(&self.interval_ms.to_ne_bytes());
+
+        if let Err(e) = self.file.seek(SeekFrom::Start(0)).and_then(|_| self.file.write_all(&buf)) {
+            return Err(Error::io(ErrorQueue::FailWrite, "write time index header", &self.file_name, e));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use crate::queue::Queue;
+use std::io;
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+pub use self::linux::InfoWatcher;
+
+#[cfg(not(target_os = "linux"))]
+pub use self::other::InfoWatcher;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::*;
+    use inotify::{Inotify, WatchDescriptor, WatchMask};
+    use std::ffi::OsString;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Instant;
+
+    // Watches the info files of the queue: <queue>_info_queue in base path and <queue>_info_push of the current part
+    pub struct InfoWatcher {
+        inotify: Inotify,
+        part: Option<(u32, WatchDescriptor)>,
+        info_queue: OsString,
+        info_push: OsString,
+        buf: Vec<u8>,
+    }
+
+    impl InfoWatcher {
+        pub fn new(queue: &Queue) -> io::Result<InfoWatcher> {
+            let inotify = Inotify::init()?;
+            inotify.watches().add(&queue.base_path, WatchMask::MODIFY | WatchMask::CREATE | WatchMask::MOVED_TO)?;
+
+            Ok(InfoWatcher {
+                inotify,
+                part: None,
+                info_queue: OsString::from(queue.name.to_owned() + "_info_queue"),
+                info_push: OsString::from(queue.name.to_owned() + "_info_push"),
+                buf: vec![0; 4096],
+            })
+        }
+
+        pub fn watch_part(&mut self, queue: &Queue, part_id: u32) -> io::Result<()> {
+            if let Some((id, wd)) = &self.part {
+                if *id == part_id {
+                    return Ok(());
+                }
+                if let Err(e) = self.inotify.watches().remove(wd.clone()) {
+                    debug!("[queue:watch] fail remove watch of part {}, err={}", id, e);
+                }
+                self.part = None;
+            }
+
+            let part_path = queue.base_path.to_owned() + "/" + &queue.name + "-" + &part_id.to_string();
+            let wd = self.inotify.watches().add(part_path, WatchMask::MODIFY | WatchMask::CREATE | WatchMask::MOVED_TO)?;
+            self.part = Some((part_id, wd));
+
+            Ok(())
+        }
+
+        // Returns true if the info files have been changed, false on timeout
+        pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+            let deadline = Instant::now() + timeout;
+
+            loop {
+                let left = deadline.saturating_duration_since(Instant::now());
+                let mut pfd = libc::pollfd {
+                    fd: self.inotify.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+
+                let res = unsafe { libc::poll(&mut pfd, 1, left.as_millis().min(i32::MAX as u128) as i32) };
+                if res < 0 {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+
+                if res == 0 {
+                    return Ok(false);
+                }
+
+                let mut changed = false;
+                match self.inotify.read_events(&mut self.buf) {
+                    Ok(events) => {
+                        for event in events {
+                            if event.name.is_some_and(|n| n == self.info_queue || n == self.info_push) {
+                                changed = true;
+                            }
+                        }
+                    },
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                    Err(e) => return Err(e),
+                }
+
+                if changed {
+                    return Ok(true);
+                }
+
+                if Instant::now() >= deadline {
+                    return Ok(false);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod other {
+    use super::*;
+
+    pub struct InfoWatcher {}
+
+    impl InfoWatcher {
+        pub fn new(_queue: &Queue) -> io::Result<InfoWatcher> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "file change notifications are supported only on linux"))
+        }
+
+        pub fn watch_part(&mut self, _queue: &Queue, _part_id: u32) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub fn wait(&mut self, _timeout: Duration) -> io::Result<bool> {
+            Ok(false)
+        }
+    }
+}